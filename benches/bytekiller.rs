@@ -0,0 +1,27 @@
+// Unpacking speed of a resource the size of a large part's video data, as
+// it's done for every scene load.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use oorw::bytekiller;
+
+// 64 KiB of runs of a few byte values, often repeating earlier ones, which
+// packs to a ratio similar to the game's resources. Packed with the test
+// packer, `testing::pack`.
+const PACKED: &[u8] = include_bytes!("data/resource.bin");
+const LEN: usize = 64 * 1024;
+
+fn unpack(c: &mut Criterion) {
+    let mut buf = vec![0; LEN];
+    buf[..PACKED.len()].copy_from_slice(PACKED);
+
+    c.bench_function("unpack", |b| {
+        b.iter_batched_ref(
+            || buf.clone(),
+            |buf| bytekiller::unpack(buf, PACKED.len()).expect("checksum mismatch"),
+            BatchSize::LargeInput,
+        )
+    });
+}
+
+criterion_group!(benches, unpack);
+criterion_main!(benches);
@@ -0,0 +1,579 @@
+#[cfg(all(unix, feature = "control-socket"))]
+use oorw::control;
+use oorw::input_log::InputLog;
+use oorw::{
+    captions, data, host, lockstep, mem, photo, run_frame, run_frame_unpaced, script, source,
+    video, watch, Game, HostBackend, Memory, NullHost, SdlHost, Vm, INTRO_PART,
+};
+use std::io::Write;
+use std::str::FromStr;
+
+// Frames run by `--dump-palette` waiting for the scripts to load a palette.
+const PALETTE_WAIT_FRAMES: u32 = 100;
+
+// Consecutive frames without runnable tasks after which `--exit-on-idle`
+// quits. Tasks may be restarted by the next frame, so a single idle frame
+// isn't enough.
+const IDLE_EXIT_FRAMES: u32 = 5;
+
+// Writes hash of the last frame, for `--verify` to compare with.
+fn log_frame_hash(g: &Game, log: &mut impl Write) {
+    if let Err(e) = writeln!(log, "{:016x}", lockstep::frame_hash(g)) {
+        log::warn!("unable to write hash log: {}", e);
+    }
+}
+
+// Replays recorded input and compares the hash of every frame with
+// the recorded run. Exits the process with an error on the first mismatch.
+fn verify(g: &mut Game, input: &InputLog, expected: &[u64]) {
+    match lockstep::verify_replay(g, input, expected) {
+        Ok(frames) if frames == expected.len() => println!("verified {} frames", frames),
+        Ok(frames) => {
+            eprintln!("input ends after {} of {} frames", frames, expected.len());
+            std::process::exit(1);
+        }
+        Err(d) => {
+            eprintln!(
+                "frame {} diverged: expected {:016x}, got {:016x}",
+                d.frame, d.expected, d.actual
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+// Sets input of the next frame from the replay and records it. Returns
+// false when the replay has no input for the next frame.
+fn prepare_input(g: &mut Game, replay: Option<&InputLog>, record: Option<&mut InputLog>) -> bool {
+    if let Some(replay) = replay {
+        match replay.frames().get(g.frame_count() as usize) {
+            Some(&state) => g.set_input(state),
+            None => return false,
+        }
+    }
+    if let Some(record) = record {
+        record.push(g.input_snapshot());
+    }
+    true
+}
+
+fn read_input_log(path: &str) -> InputLog {
+    std::fs::File::open(path)
+        .and_then(|f| InputLog::load(&mut std::io::BufReader::new(f)))
+        .unwrap_or_else(|e| {
+            eprintln!("unable to read {}: {}", path, e);
+            std::process::exit(1);
+        })
+}
+
+// Runs frames as fast as possible and reports how long they took.
+fn benchmark(g: &mut Game, frames: u64) {
+    let result = oorw::benchmark(g, frames);
+    println!("frames: {}", result.frames);
+    println!("total: {:.3} s", result.total.as_secs_f64());
+    if let Some(average) = result.average_frame() {
+        println!("average frame: {:.3} ms", average.as_secs_f64() * 1000.0);
+    }
+    println!("hash: {:016x}", result.hash);
+}
+
+fn print_opcode_profile(hist: &[(u8, u64)]) {
+    if hist.is_empty() {
+        return;
+    }
+    let total: u64 = hist.iter().map(|(_, count)| count).sum();
+    println!("{:>12}  {:>6}  opcode", "count", "%");
+    for &(opcode, count) in hist {
+        println!(
+            "{:>12}  {:>6.2}  0x{:02X} {}",
+            count,
+            count as f64 * 100.0 / total as f64,
+            opcode,
+            oorw::script::opcode_name(opcode)
+        );
+    }
+}
+
+pub fn main() {
+    env_logger::init();
+
+    let matches = clap::App::new("Another World in Rust")
+        .version("1.0")
+        .args_from_usage(
+            "--fullscreen 'Display in fullscreen'
+            --data-dir=[DIR] 'Directory with memlist.bin and bank files or a package'
+            --pixel-format=[FMT] 'Output format: rgb565 (default), argb8888 or indexed8'
+            --scene=[NUM] 'Start from given scene'
+            --play-sound=[RES,FREQ,VOL,CHAN] 'Play given sound resource on start (debug)'
+            --play-music=[RES] 'Play given music resource on start (debug)'
+            --ega-pal 'Use EGA palette'
+            --pal-expansion=[MODE] 'How palette colors are stored: replicate (default), scale or raw8'
+            --palette-remap=[LIST] 'Display colors using 16 comma-separated palette indices'
+            --gamma=[F] 'Apply gamma correction to palette colors (default 1.0)'
+            --seed=[NUM] 'Seed for the random number generator'
+            --dump-bitmaps=[DIR] 'Write loaded bitmaps as PNG files to given directory'
+            --export-vectors=[FILE] 'Write draw operations of every frame to given file as JSON lines'
+            --data-size=[KB] 'Size of resource memory in KiB (default 1024)'
+            --cache-dir=[DIR] 'Keep unpacked resources in given directory for faster loading'
+            --mod-dir=[DIR] 'Replace resources with unpacked files named by resource number'
+            --watch-data 'Restart current part when data or mod files change'
+            --dump-strings 'Print all game strings with their IDs and exit'
+            --dump-palette=[FILE] 'Write first palette of the starting part to given .gpl or hex file and exit'
+            --validate 'Check that resources of all parts are available and exit'
+            --debug 'Collect debug state every frame'
+            --scene-fade 'Crossfade between parts instead of a hard cut'
+            --scale=[N] 'Open window at given multiple of 320x200, height stretched by --pixel-aspect: 4 opens 1280x960 by default'
+            --vsync 'Synchronize presenting with display refresh'
+            --flip-h 'Mirror the displayed image horizontally'
+            --pixel-aspect=[ASPECT] 'Shape of pixels: 4-3 as on original displays (default) or square'
+            --crop=[T,B,L,R] 'Hide given number of pixels at top, bottom, left and right edges'
+            --strict-video 'Mark shapes skipped due to bad data on screen'
+            --no-flip 'Draw everything to a single page (debug)'
+            --force-pal=[NUM] 'Always use given palette (debug)'
+            --attract=[SECS] 'Restart the intro after given idle time (off by default)'
+            --stereo-width=[F] 'Separation of music channels from 0 (mono) to 1 (default)'
+            --mono 'Play music in mono, same as --stereo-width=0'
+            --amiga-audio 'Play music without interpolation and with Amiga low-pass filter'
+            --no-audio 'Run without sound and music output'
+            --volume=[PERCENT] 'Initial volume from 0 to 100 (default 100)'
+            --mute 'Start muted'
+            --sfx-captions=[FILE] 'Show captions for sounds listed in given file'
+            --sound-channels=[N] 'Number of sound effect channels (default 4)'
+            --slow-motion=[F] 'Speed while holding Backtick key (default 0.25)'
+            --precise-timing 'Busy-wait end of every frame for steadier pacing, uses more CPU'
+            --reference-timing 'Count 50 Hz vertical blanks like the original instead of measuring frame time'
+            --benchmark=[N] 'Run given number of frames without display and audio, then print timing and hash'
+            --frames=[N] 'Run given number of frames without display and audio as fast as possible and exit'
+            --print-hash 'Print hash of the final frame on exit'
+            --hash-log=[FILE] 'Write hash of every frame to given file'
+            --verify=[FILE] 'Replay input of --replay-input and compare frame hashes with a --hash-log file'
+            --record-input=[FILE] 'Write input of every frame to given file on exit'
+            --replay-input=[FILE] 'Use input recorded with --record-input and exit when it ends'
+            --control-socket=[PATH] 'Accept commands on given Unix socket (control-socket feature)'
+            --exit-on-idle 'Exit once scripts stop running, e.g. after the intro'
+            --call-stack=[N] 'Maximum depth of script calls (default 64)'
+            --profile-opcodes 'Print histogram of executed opcodes on exit'
+            --coverage=[FILE] 'Write offsets of executed script instructions to given file on exit'",
+        )
+        .get_matches();
+
+    if matches.is_present("dump-strings") {
+        for (id, text) in video::strings() {
+            println!("0x{:03X}\t{:?}", id, text);
+        }
+        return;
+    }
+
+    let mut host_opts = host::Options {
+        fullscreen: matches.is_present("fullscreen"),
+        flip_h: matches.is_present("flip-h"),
+        vsync: matches.is_present("vsync"),
+        scene_fade: matches.is_present("scene-fade"),
+        audio: !matches.is_present("no-audio"),
+        muted: matches.is_present("mute"),
+        sound_channels: matches
+            .value_of("sound-channels")
+            .and_then(|s| u8::from_str(s).ok())
+            .unwrap_or(4),
+        scale: matches
+            .value_of("scale")
+            .and_then(|s| u32::from_str(s).ok()),
+        ..Default::default()
+    };
+    if let Some(fmt) = matches.value_of("pixel-format") {
+        match fmt.parse() {
+            Ok(fmt) => host_opts.pixel_format = fmt,
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    if let Some(volume) = matches.value_of("volume") {
+        match u8::from_str(volume) {
+            Ok(v) if v <= 100 => host_opts.volume = v,
+            _ => log::warn!("invalid volume {}, expected 0 to 100", volume),
+        }
+    }
+    if let Some(aspect) = matches.value_of("pixel-aspect") {
+        match aspect.parse() {
+            Ok(aspect) => host_opts.pixel_aspect = aspect,
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    if let Some(crop) = matches.value_of("crop") {
+        match crop.parse() {
+            Ok(crop) => host_opts.crop = crop,
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+
+    let data_dir = std::path::Path::new(matches.value_of("data-dir").unwrap_or("."));
+    let source = source::Source::detect(data_dir).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let data_size = matches
+        .value_of("data-size")
+        .and_then(|s| usize::from_str(s).ok())
+        .map_or(mem::DATA_SIZE, |kb| kb * 1024);
+
+    let mut mem = Memory::new(source, data_size).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+    if let Some(dir) = matches.value_of("mod-dir") {
+        match mem::read_overrides(dir.as_ref()) {
+            Ok(overrides) => mem.set_overrides(overrides),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("validate") {
+        let problems = mem::validate_parts(&mem);
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            std::process::exit(1);
+        }
+        println!("all parts are complete");
+        return;
+    }
+
+    // Runs with a fixed number of frames don't need a display or audio.
+    let host: Box<dyn HostBackend> = if ["benchmark", "frames", "verify"]
+        .iter()
+        .any(|name| matches.is_present(name))
+    {
+        Box::new(NullHost::new(host_opts.sound_channels))
+    } else {
+        Box::new(SdlHost::new(&host_opts))
+    };
+
+    let mut game = Game::new(host, mem);
+    if let Some(size) = matches
+        .value_of("call-stack")
+        .and_then(|s| usize::from_str(s).ok())
+    {
+        game.vm = Vm::new(size);
+    }
+    game.captions = matches.value_of("sfx-captions").map(|path| {
+        captions::Captions::load(path.as_ref()).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    });
+    if let Some(speed) = matches
+        .value_of("slow-motion")
+        .and_then(|s| f32::from_str(s).ok())
+    {
+        game.slow_motion_speed = speed.clamp(0.05, 1.0);
+    }
+    game.precise_timing = matches.is_present("precise-timing");
+    game.reference_timing = matches.is_present("reference-timing");
+    game.attract_timeout = matches
+        .value_of("attract")
+        .and_then(|s| u64::from_str(s).ok())
+        .map(std::time::Duration::from_secs);
+    if matches.is_present("debug") {
+        game.debug = Some(Default::default());
+    }
+
+    game.video.set_use_ega_pal(matches.is_present("ega-pal"));
+    if let Some(expansion) = matches.value_of("pal-expansion") {
+        match expansion.parse() {
+            Ok(expansion) => game.video.set_pal_expansion(expansion),
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    game.video.set_no_flip(matches.is_present("no-flip"));
+    game.video.set_forced_pal_num(
+        matches
+            .value_of("force-pal")
+            .and_then(|s| u8::from_str(s).ok()),
+    );
+
+    if let Some(gamma) = matches.value_of("gamma") {
+        let result = f32::from_str(gamma)
+            .map_err(|e| e.to_string())
+            .and_then(|gamma| game.video.rndr.set_gamma(gamma));
+        if let Err(e) = result {
+            log::warn!("invalid --gamma: {}", e);
+        }
+    }
+
+    if let Some(list) = matches.value_of("palette-remap") {
+        let indices: Result<Vec<u8>, _> = list.split(',').map(|s| s.trim().parse()).collect();
+        let result = match indices {
+            Ok(indices) if indices.len() == 16 => {
+                let mut remap = [0; 16];
+                remap.copy_from_slice(&indices);
+                game.video.rndr.set_remap(Some(remap))
+            }
+            _ => Err("expected 16 comma-separated palette indices".to_owned()),
+        };
+        if let Err(e) = result {
+            log::warn!("invalid --palette-remap: {}", e);
+        }
+    }
+
+    if matches.is_present("mono") {
+        game.music.set_stereo_width(0.0);
+    } else if let Some(width) = matches
+        .value_of("stereo-width")
+        .and_then(|s| f32::from_str(s).ok())
+    {
+        game.music.set_stereo_width(width);
+    }
+    game.music
+        .set_amiga_audio(matches.is_present("amiga-audio"));
+
+    game.video
+        .set_bitmap_dump_dir(matches.value_of("dump-bitmaps").map(Into::into));
+
+    game.mem
+        .set_cache_dir(matches.value_of("cache-dir").map(Into::into));
+
+    game.video.set_strict(matches.is_present("strict-video"));
+
+    if let Some(path) = matches.value_of("export-vectors") {
+        match video::vectors::Recorder::create(path.as_ref()) {
+            Ok(recorder) => game.video.set_vector_recorder(Some(recorder)),
+            Err(e) => {
+                eprintln!("unable to create {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if matches.is_present("profile-opcodes") {
+        game.vm.enable_opcode_profile();
+    }
+    if matches.is_present("coverage") {
+        game.vm.enable_coverage();
+    }
+
+    if let Some(seed) = matches.value_of("seed").and_then(|s| i16::from_str(s).ok()) {
+        game.vm.set_random_seed(seed);
+    }
+
+    let scene = matches
+        .value_of("scene")
+        .and_then(|s| u16::from_str(s).ok())
+        .unwrap_or(INTRO_PART);
+
+    let part = if scene < 36 {
+        let (part, pos) = data::SCENE_POS[usize::from(scene)];
+        script::restart_at(&mut game, part, pos);
+        part
+    } else {
+        script::restart_at(&mut game, scene, -1);
+        scene
+    };
+    if !game.is_part_ready(part) {
+        eprintln!(
+            "unable to load part {}, peak resource memory usage was {} of {} bytes",
+            part,
+            game.mem.peak_usage(),
+            game.mem.data.len()
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(args) = matches.value_of("play-sound") {
+        let args: Vec<_> = args.split(',').map(u16::from_str).collect();
+        match args[..] {
+            [Ok(res), Ok(freq), Ok(vol), Ok(chan)]
+                if freq <= 0xFF
+                    && vol <= 0xFF
+                    && chan < u16::from(game.host.sound_channel_count()) =>
+            {
+                script::debug_play_sound(&mut game, res, freq as u8, vol as u8, chan as u8)
+            }
+            _ => log::warn!("invalid --play-sound arguments"),
+        }
+    }
+
+    if let Some(res) = matches
+        .value_of("play-music")
+        .and_then(|s| u16::from_str(s).ok())
+    {
+        script::debug_play_music(&mut game, res);
+    }
+
+    if let Some(frames) = matches.value_of("benchmark") {
+        let frames = u64::from_str(frames).unwrap_or_else(|e| {
+            eprintln!("invalid number of frames {}: {}", frames, e);
+            std::process::exit(1);
+        });
+        benchmark(&mut game, frames);
+        return;
+    }
+
+    if let Some(path) = matches.value_of("dump-palette") {
+        // Palettes are loaded by scripts, usually within the first frames.
+        for _ in 0..PALETTE_WAIT_FRAMES {
+            if game.video.current_pal_num().is_some() {
+                break;
+            }
+            run_frame_unpaced(&mut game);
+        }
+        if let Err(e) = video::write_palette(&game, path.as_ref()) {
+            eprintln!("unable to write {}: {}", path, e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    let replay = matches.value_of("replay-input").map(read_input_log);
+    let frame_limit = matches
+        .value_of("frames")
+        .and_then(|s| u64::from_str(s).ok());
+
+    if let Some(path) = matches.value_of("verify") {
+        let mut expected =
+            lockstep::read_hash_log(std::path::Path::new(path)).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            });
+        // Verification runs as many frames as were logged unless told
+        // otherwise. Runs recorded without input had none.
+        if let Some(limit) = frame_limit {
+            expected.truncate(limit as usize);
+        }
+        let input = replay.unwrap_or_else(|| {
+            let mut input = InputLog::new();
+            for _ in 0..expected.len() {
+                input.push(Default::default());
+            }
+            input
+        });
+        verify(&mut game, &input, &expected);
+        return;
+    }
+
+    let mut hash_log = matches.value_of("hash-log").map(|path| {
+        let f = std::fs::File::create(path).unwrap_or_else(|e| {
+            eprintln!("unable to create {}: {}", path, e);
+            std::process::exit(1);
+        });
+        std::io::BufWriter::new(f)
+    });
+
+    let mod_dir = matches.value_of("mod-dir").map(std::path::Path::new);
+    let mut watcher = if matches.is_present("watch-data") {
+        let dirs = std::iter::once(data_dir).chain(mod_dir).map(Into::into);
+        Some(watch::Watcher::new(dirs.collect()))
+    } else {
+        None
+    };
+
+    #[cfg(all(unix, feature = "control-socket"))]
+    let mut control = matches.value_of("control-socket").map(|path| {
+        control::ControlSocket::bind(path.as_ref()).unwrap_or_else(|e| {
+            eprintln!("unable to open control socket {}: {}", path, e);
+            std::process::exit(1);
+        })
+    });
+    #[cfg(not(all(unix, feature = "control-socket")))]
+    {
+        if matches.is_present("control-socket") {
+            log::warn!("built without control socket support");
+        }
+    }
+
+    let mut recording = matches.value_of("record-input").map(|_| InputLog::new());
+
+    let exit_on_idle = matches.is_present("exit-on-idle");
+    let mut idle_frames = 0;
+
+    while !game.host.wants_quit() {
+        if exit_on_idle && game.vm.all_tasks_halted() && game.next_part().is_none() {
+            idle_frames += 1;
+            if idle_frames >= IDLE_EXIT_FRAMES {
+                log::info!("no tasks running, exiting");
+                break;
+            }
+        } else {
+            idle_frames = 0;
+        }
+
+        if let Some(limit) = frame_limit {
+            if game.frame_count() >= limit
+                || !prepare_input(&mut game, replay.as_ref(), recording.as_mut())
+            {
+                break;
+            }
+            run_frame_unpaced(&mut game);
+            if let Some(log) = &mut hash_log {
+                log_frame_hash(&game, log);
+            }
+        } else if !photo::is_active(&game) && (!game.host.wants_pause() || game.host.take_step()) {
+            if !prepare_input(&mut game, replay.as_ref(), recording.as_mut()) {
+                log::info!("end of input replay");
+                break;
+            }
+            // Music for a stepped frame is produced by pacing as usual.
+            run_frame(&mut game);
+            script::pace_frame(&mut game);
+            if let Some(log) = &mut hash_log {
+                log_frame_hash(&game, log);
+            }
+        } else {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+        }
+        host::process_input(&mut game);
+        #[cfg(all(unix, feature = "control-socket"))]
+        {
+            if let Some(control) = &mut control {
+                control.poll(&mut game);
+            }
+        }
+        oorw::check_attract_mode(&mut game);
+        if watcher.as_mut().is_some_and(watch::Watcher::poll) {
+            oorw::reload_data(&mut game, mod_dir);
+        }
+    }
+
+    print_opcode_profile(&game.vm.opcode_profile());
+
+    if let Some(path) = matches.value_of("coverage") {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut w = std::io::BufWriter::new(f);
+            game.vm.write_coverage(&mut w)?;
+            w.flush()
+        });
+        if let Err(e) = result {
+            eprintln!("unable to write {}: {}", path, e);
+        }
+    }
+
+    if let (Some(path), Some(recording)) = (matches.value_of("record-input"), &recording) {
+        let result = std::fs::File::create(path).and_then(|f| {
+            let mut w = std::io::BufWriter::new(f);
+            recording.save(&mut w)?;
+            w.flush()
+        });
+        if let Err(e) = result {
+            eprintln!("unable to write {}: {}", path, e);
+        }
+    }
+
+    if let Some(recorder) = game.video.take_vector_recorder() {
+        if let Err(e) = recorder.finish() {
+            eprintln!("unable to write vectors: {}", e);
+        }
+    }
+
+    log::info!(
+        "peak resource memory usage: {} of {} bytes",
+        game.mem.peak_usage(),
+        game.mem.data.len()
+    );
+
+    if matches.is_present("print-hash") {
+        println!("{:016x}", lockstep::frame_hash(&game));
+    }
+}
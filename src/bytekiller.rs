@@ -7,6 +7,8 @@ struct Ctx<'a> {
     len: usize,
     crc: u32,
     bits: u32,
+    // Set when the data refers outside of the buffer.
+    corrupt: bool,
 }
 
 impl<'a> Ctx<'a> {
@@ -20,20 +22,38 @@ impl<'a> Ctx<'a> {
     }
 }
 
-pub fn unpack(buf: &mut [u8], packed_len: usize) {
+// Unpacks in place: `buf` starts with `packed_len` bytes of packed data and
+// receives the unpacked data. Fails when the header is invalid, the data
+// doesn't fit or refers outside of `buf`, or its checksum doesn't match.
+pub fn unpack(buf: &mut [u8], packed_len: usize) -> Result<(), String> {
+    // Header of three words is read from the end.
+    if packed_len < 12 || packed_len > buf.len() {
+        return Err(format!(
+            "packed size {} is invalid for buffer of {} bytes",
+            packed_len,
+            buf.len()
+        ));
+    }
     let mut src_pos = packed_len - 4;
 
     let len = BE::read_u32(&buf[src_pos..]) as usize;
     src_pos -= 4;
 
-    assert!(len <= buf.len(), "output buffer too small");
+    if len == 0 || len > buf.len() {
+        return Err(format!(
+            "unpacked size {} is invalid for buffer of {} bytes",
+            len,
+            buf.len()
+        ));
+    }
     let dst_pos = len - 1;
 
     let mut crc = BE::read_u32(&buf[src_pos..]);
     src_pos -= 4;
 
     let bits = BE::read_u32(&buf[src_pos..]);
-    src_pos -= 4;
+    // Data with no words past the first one wraps around, see `next_bit`.
+    src_pos = src_pos.wrapping_sub(4);
 
     crc ^= bits;
 
@@ -44,6 +64,7 @@ pub fn unpack(buf: &mut [u8], packed_len: usize) {
         len,
         crc,
         bits,
+        corrupt: false,
     };
 
     while ctx.len > 0 {
@@ -68,15 +89,36 @@ pub fn unpack(buf: &mut [u8], packed_len: usize) {
         }
     }
 
-    assert!(ctx.len == 0 && ctx.crc == 0, "bytekiller failure");
+    if ctx.corrupt {
+        return Err("packed data is corrupt".to_owned());
+    }
+    if ctx.crc != 0 {
+        return Err("checksum mismatch".to_owned());
+    }
+    Ok(())
 }
 
+// Reads `count` bits at once from the current word where possible. Bits are
+// consumed from the LSB, while output is built MSB first, so each chunk is
+// reversed. Only word reloads go through `next_bit`.
 fn rdd1bits(ctx: &mut Ctx, count: usize) -> i32 {
-    let mut output = 0;
-    for _ in 0..count {
-        output = (output << 1) | i32::from(next_bit(ctx));
+    let mut output = 0u32;
+    let mut count = count as u32;
+    while count > 0 {
+        // Data bits left below the sentinel bit.
+        let avail = 31 - ctx.bits.leading_zeros();
+        if avail == 0 {
+            output = (output << 1) | u32::from(next_bit(ctx));
+            count -= 1;
+            continue;
+        }
+        let n = std::cmp::min(avail, count);
+        let chunk = ctx.bits & ((1 << n) - 1);
+        output = (output << n) | (chunk.reverse_bits() >> (32 - n));
+        ctx.bits >>= n;
+        count -= n;
     }
-    output
+    output as i32
 }
 
 fn getd3chr(ctx: &mut Ctx, bits_count: usize, input_len: usize) {
@@ -92,16 +134,17 @@ fn getd3chr(ctx: &mut Ctx, bits_count: usize, input_len: usize) {
 
 fn copyd3bytes(ctx: &mut Ctx, bits_count: usize, count: usize) {
     let count = ctx.adjust_len(count);
-    let offset = rdd1bits(ctx, bits_count);
+    // Offsets are at most 12 bits, so never negative.
+    let offset = rdd1bits(ctx, bits_count) as usize;
+    if ctx.dst_pos + offset >= ctx.buf.len() {
+        ctx.corrupt = true;
+        ctx.len = 0;
+        return;
+    }
 
     for i in 0..count {
         let output_pos = ctx.dst_pos - i;
-        let input_pos = if offset >= 0 {
-            output_pos + (offset as usize)
-        } else {
-            output_pos - (-offset as usize)
-        };
-        ctx.buf[output_pos] = ctx.buf[input_pos];
+        ctx.buf[output_pos] = ctx.buf[output_pos + offset];
     }
 
     ctx.dst_pos = ctx.dst_pos.wrapping_sub(count);
@@ -111,7 +154,17 @@ fn next_bit(ctx: &mut Ctx) -> bool {
     let mut carry = (ctx.bits & 1) != 0;
     ctx.bits >>= 1;
     if ctx.bits == 0 {
-        ctx.bits = BE::read_u32(&ctx.buf[ctx.src_pos..]);
+        // Reading past the start wraps `src_pos` around, out of the buffer.
+        let word = match ctx.buf.get(ctx.src_pos..).and_then(|w| w.get(..4)) {
+            Some(word) => word,
+            None => {
+                ctx.corrupt = true;
+                ctx.len = 0;
+                ctx.bits = 1 << 31;
+                return false;
+            }
+        };
+        ctx.bits = BE::read_u32(word);
         ctx.src_pos = ctx.src_pos.wrapping_sub(4);
         ctx.crc ^= ctx.bits;
         carry = (ctx.bits & 1) != 0;
@@ -119,3 +172,91 @@ fn next_bit(ctx: &mut Ctx) -> bool {
     }
     carry
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::pack;
+
+    // Text with repeats at varying distances, like most resources.
+    fn sample() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..200 {
+            let line = format!("line {} of {}: {}\n", i, 200, "abc".repeat(i % 7));
+            data.extend_from_slice(line.as_bytes());
+        }
+        data
+    }
+
+    fn unpacked(packed: &[u8], len: usize) -> Result<Vec<u8>, String> {
+        let mut buf = vec![0; len];
+        buf[..packed.len()].copy_from_slice(packed);
+        unpack(&mut buf, packed.len())?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn packed_data_unpacks_in_place() {
+        let data = sample();
+        let packed = pack(&data).unwrap();
+        assert!(packed.len() < data.len() / 2);
+        assert_eq!(unpacked(&packed, data.len()).unwrap(), data);
+    }
+
+    #[test]
+    fn checksum_mismatch_is_an_error() {
+        let data = sample();
+        let mut packed = pack(&data).unwrap();
+        let crc = packed.len() - 8;
+        packed[crc] ^= 1;
+        assert!(unpacked(&packed, data.len()).is_err());
+    }
+
+    // Header of given first word, checksum and unpacked size, in a buffer of
+    // 16 bytes.
+    fn header(bits: u32, crc: u32, len: u32) -> [u8; 16] {
+        let mut buf = [0; 16];
+        BE::write_u32(&mut buf[0..], bits);
+        BE::write_u32(&mut buf[4..], crc);
+        BE::write_u32(&mut buf[8..], len);
+        buf
+    }
+
+    #[test]
+    fn malformed_header_is_an_error() {
+        assert!(unpack(&mut [0; 8], 8).is_err());
+        assert!(unpack(&mut header(1, 1, 4), 20).is_err());
+        assert!(unpack(&mut header(1, 1, 0), 12).is_err());
+        assert!(unpack(&mut header(1, 1, 17), 12).is_err());
+    }
+
+    #[test]
+    fn data_referring_outside_buffer_is_an_error() {
+        // Needs a word before the first one.
+        assert_eq!(
+            unpack(&mut header(1, 1, 4), 12),
+            Err("packed data is corrupt".to_owned())
+        );
+        // Copies 2 bytes from 255 bytes further.
+        let bits = 0x400 | 0xFF << 2 | 0b10;
+        assert_eq!(
+            unpack(&mut header(bits, bits, 4), 12),
+            Err("packed data is corrupt".to_owned())
+        );
+    }
+
+    #[test]
+    fn incompressible_data_is_not_packed() {
+        let mut x = 1u32;
+        let data: Vec<u8> = (0..1000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 17;
+                x ^= x << 5;
+                x as u8
+            })
+            .collect();
+        assert_eq!(pack(&data), None);
+        assert_eq!(pack(&[]), None);
+    }
+}
@@ -0,0 +1,116 @@
+// Captions shown when sounds play, for players who can't hear them. Enabled
+// with `--sfx-captions` pointing to a text file with one caption per line:
+//
+//   # comment
+//   0x4C = door opens
+//   91 = gunshot
+//
+// Resource numbers are decimal or hexadecimal with "0x" prefix. Sounds
+// without a caption are ignored. Only printable ASCII is displayed, as that
+// is what the game font has.
+
+use crate::Game;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const CAPTION_TIME: Duration = Duration::from_secs(2);
+
+pub struct Captions {
+    table: HashMap<u16, String>,
+    // Caption on screen and when it was shown.
+    current: Option<(String, Instant)>,
+}
+
+impl Captions {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+        let mut table = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parsed = line.split_once('=').and_then(|(num, caption)| {
+                let num = num.trim();
+                let num = match num.strip_prefix("0x") {
+                    Some(hex) => u16::from_str_radix(hex, 16).ok()?,
+                    None => num.parse().ok()?,
+                };
+                let caption: String = caption
+                    .trim()
+                    .chars()
+                    .filter(|c| (' '..='~').contains(c))
+                    .collect();
+                Some((num, caption))
+            });
+            match parsed {
+                Some((num, caption)) => {
+                    table.insert(num, caption);
+                }
+                None => {
+                    return Err(format!(
+                        "{}:{}: expected `RES = TEXT`",
+                        path.display(),
+                        i + 1
+                    ))
+                }
+            }
+        }
+        Ok(Self {
+            table,
+            current: None,
+        })
+    }
+
+    // Caption to display now, if any.
+    pub fn current(&self) -> Option<&str> {
+        match &self.current {
+            Some((caption, shown)) if shown.elapsed() < CAPTION_TIME => Some(caption),
+            _ => None,
+        }
+    }
+}
+
+// Called for every sound started by the scripts.
+pub fn on_sound(g: &mut Game, res_num: u16) {
+    if let Some(captions) = &mut g.captions {
+        if let Some(caption) = captions.table.get(&res_num) {
+            captions.current = Some((caption.clone(), Instant::now()));
+        }
+    }
+}
+
+// Returns page to display instead of given one, with current caption drawn.
+pub fn draw(g: &mut Game, fb: u8) -> u8 {
+    match g.captions.as_ref().and_then(Captions::current) {
+        Some(caption) => crate::video::draw_caption(&mut g.video, fb, caption),
+        None => fb,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn captioned_sound_queues_caption() {
+        let code = [
+            0x18, 0x00, 0x4D, 0x10, 0x3F, 0x00, // snd 0x4D, 16, 63, 0
+            0x06, // yield
+            0x18, 0x00, 0x4C, 0x10, 0x3F, 0x00, // snd 0x4C, 16, 63, 0
+            0x06, 0x07, 0x00, 0x0D, // yield; b 0x000D
+        ];
+        let mut g = testing::game("captioned_sound_queues_caption", &code);
+        let path = testing::temp_dir("captioned_sound_queues_caption_table").join("captions.txt");
+        std::fs::write(&path, "# test\n0x4C = door opens\n91 = gunshot\n").unwrap();
+        g.captions = Some(Captions::load(&path).unwrap());
+
+        crate::run_frame(&mut g);
+        assert_eq!(g.captions.as_ref().unwrap().current(), None);
+        crate::run_frame(&mut g);
+        assert_eq!(g.captions.as_ref().unwrap().current(), Some("door opens"));
+    }
+}
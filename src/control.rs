@@ -0,0 +1,146 @@
+// Remote control over a Unix domain socket, for test harnesses and bots.
+// Enabled with `control-socket` feature and `--control-socket` option.
+//
+// Clients send commands, one per line, and get a single line reply to each:
+// a value for queries, "ok" or "error: <reason>". Commands:
+//
+//   press KEY      hold a key: left, right, up, down or button
+//   release KEY    release a held key
+//   char C         type a character, as on the password screen
+//   reg ID         value of given VM register, ID in decimal or with "0x"
+//   part           number of current part
+//   frame          number of frames run so far
+//   quit           exit the game
+//
+// Input set here is overwritten by the keyboard when keys are used, like
+// any two input devices would.
+
+use crate::Game;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+pub struct ControlSocket {
+    listener: UnixListener,
+    clients: Vec<Client>,
+}
+
+struct Client {
+    reader: BufReader<UnixStream>,
+    // Start of a command still waiting for the rest of its line.
+    line: String,
+}
+
+impl ControlSocket {
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            clients: Vec::new(),
+        })
+    }
+
+    // Accepts new clients and runs all complete commands received, without
+    // blocking. Disconnected clients are dropped.
+    pub fn poll(&mut self, g: &mut Game) {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => match stream.set_nonblocking(true) {
+                    Ok(()) => self.clients.push(Client {
+                        reader: BufReader::new(stream),
+                        line: String::new(),
+                    }),
+                    Err(e) => log::warn!("unable to set up control client: {}", e),
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    log::warn!("unable to accept control client: {}", e);
+                    break;
+                }
+            }
+        }
+
+        self.clients.retain_mut(|client| serve(client, g).is_ok());
+    }
+}
+
+// Returns an error when the client should be dropped.
+fn serve(client: &mut Client, g: &mut Game) -> io::Result<()> {
+    loop {
+        // Partial lines stay in `client.line` until the rest arrives.
+        match client.reader.read_line(&mut client.line) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) if !client.line.ends_with('\n') => return Ok(()),
+            Ok(_) => {
+                let reply =
+                    run_command(g, client.line.trim()).unwrap_or_else(|e| format!("error: {}", e));
+                writeln!(client.reader.get_mut(), "{}", reply)?;
+                client.line.clear();
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn run_command(g: &mut Game, command: &str) -> Result<String, String> {
+    let mut words = command.split_whitespace();
+    let name = words.next().unwrap_or("");
+    let arg = words.next();
+    match (name, arg) {
+        ("press", Some(key)) | ("release", Some(key)) => {
+            let down = name == "press";
+            match key {
+                "left" => g.input.left = down,
+                "right" => g.input.right = down,
+                "up" => g.input.up = down,
+                "down" => g.input.down = down,
+                "button" => g.input.button = down,
+                _ => return Err(format!("unknown key {}", key)),
+            }
+        }
+        ("char", Some(c)) if c.len() == 1 => g.input.last_char = Some(c.as_bytes()[0]),
+        ("reg", Some(id)) => {
+            let id = match id.strip_prefix("0x") {
+                Some(hex) => u8::from_str_radix(hex, 16),
+                None => id.parse(),
+            }
+            .map_err(|e| format!("invalid register {}: {}", id, e))?;
+            return Ok(g.vm.regs()[usize::from(id)].to_string());
+        }
+        ("part", None) => return Ok(g.current_part.to_string()),
+        ("frame", None) => return Ok(g.frame_count.to_string()),
+        ("quit", None) => g.host.request_quit(),
+        _ => return Err(format!("invalid command `{}`", command)),
+    }
+    Ok("ok".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn press_command_sets_input() {
+        let path = testing::temp_dir("press_command_sets_input").join("control");
+        let mut g = testing::game("press_command_sets_input", &[0x06, 0x07, 0x00, 0x00]);
+        let mut control = ControlSocket::bind(&path).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        let mut replies = BufReader::new(client.try_clone().unwrap());
+        let mut reply = String::new();
+
+        // Nothing happens until the line is complete.
+        client.write_all(b"press but").unwrap();
+        control.poll(&mut g);
+        assert!(!g.input.button);
+
+        client.write_all(b"ton\npart\n").unwrap();
+        control.poll(&mut g);
+        assert!(g.input.button);
+        replies.read_line(&mut reply).unwrap();
+        replies.read_line(&mut reply).unwrap();
+        assert_eq!(reply, "ok\n16001\n");
+    }
+}
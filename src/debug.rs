@@ -0,0 +1,37 @@
+// Engine internals gathered for debug front-ends. Collected once per frame
+// by `run_frame` only when enabled with `--debug`.
+
+use crate::{script, sfx, Game};
+
+#[derive(Default)]
+pub struct DebugState {
+    pub frame: u64,
+    pub part: u16,
+    pub screen: Option<i16>,
+    pub regs: Vec<i16>,
+    // ID and program counter of every task which isn't halted.
+    pub tasks: Vec<(usize, u16)>,
+    pub channels: [sfx::ChannelStatus; 4],
+    // Polygons drawn during the frame.
+    pub polygons: u32,
+    // Pacing of the previous frame, as frames are paced after running.
+    pub pacing: Option<script::FramePacing>,
+}
+
+pub fn update(g: &mut Game) {
+    let state = match &mut g.debug {
+        Some(state) => state,
+        None => return,
+    };
+
+    state.frame = g.frame_count;
+    state.part = g.current_part;
+    state.screen = g.screen_num;
+    state.regs.clear();
+    state.regs.extend_from_slice(g.vm.regs());
+    state.tasks.clear();
+    state.tasks.extend(g.vm.active_tasks());
+    state.channels = g.music.channel_status();
+    state.polygons = g.video.take_polygon_count();
+    state.pacing = g.vm.pacing();
+}
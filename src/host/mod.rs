@@ -0,0 +1,296 @@
+// Output and input of the game. `HostBackend` is implemented by `SdlHost`
+// for playing and by `NullHost` for running frames without a display or
+// audio device. Functions here do the game side of presenting, mixing music
+// and handling input, and pass the results to the backend.
+
+use crate::video::soft;
+use crate::{photo, script, sfx, video, Game};
+
+mod sdl;
+
+pub use sdl::{Crop, Options, PixelAspect, PresentHook, SdlHost};
+
+const MUSIC_SAMPLES_PER_FRAME: usize = (sfx::HOST_RATE as usize) / 50 * 2;
+
+// Requests from the user which need more than the backend, applied by
+// `process_input`.
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Photo(photo::Action),
+    Task(TaskKey),
+    SkipScene,
+    // Slow motion key pressed or released.
+    SlowMotion(bool),
+    DumpFrame,
+    DumpPalette,
+}
+
+// Debug keys for finding out what a task does, see `debug_tasks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskKey {
+    SelectNext,
+    ToggleFrozen,
+    Solo,
+    UnfreezeAll,
+}
+
+pub trait HostBackend {
+    // Presents page `fb`. `skipped` are positions of shapes skipped due to
+    // bad data, to be marked in strict video mode.
+    fn display_surface(&mut self, rndr: &soft::State, fb: u8, skipped: &[(i16, i16)]);
+
+    // Called before a part starts, with the page displayed last.
+    fn begin_scene_fade(&mut self, _rndr: &soft::State, _fb: u8) {}
+
+    // Whether the pixel grid overlay was toggled on.
+    fn shows_grid(&self) -> bool {
+        false
+    }
+
+    fn sound_channel_count(&self) -> u8;
+
+    // Plays `len` bytes of 8-bit signed samples recorded at `freq`. `loops`
+    // is the number of extra repeats, -1 repeats until stopped.
+    fn play_sound(
+        &mut self,
+        channel: u8,
+        freq: u16,
+        volume: u8,
+        data: &[u8],
+        len: usize,
+        loops: i32,
+    );
+
+    fn stop_sound(&mut self, channel: u8);
+
+    fn fade_out_sound(&mut self, channel: u8, ms: i32);
+
+    // Number of interleaved stereo samples which can be queued without
+    // blocking, or `None` when there's no audio output to pace music by.
+    fn music_space(&self) -> Option<usize>;
+
+    // Queues mixed music, may scale samples in place for volume.
+    fn queue_music(&mut self, samples: &mut [i16]);
+
+    // Handles pending events, updating `input` and returning what needs to
+    // be applied to the game.
+    fn process_input(&mut self, input: &mut script::Input, photo_mode: bool) -> Vec<Action>;
+
+    fn wants_quit(&self) -> bool;
+
+    fn request_quit(&mut self);
+
+    fn wants_pause(&self) -> bool {
+        false
+    }
+
+    // Whether a single frame should be run while paused.
+    fn take_step(&mut self) -> bool {
+        false
+    }
+
+    // Time between display refreshes when presenting waits for them, see
+    // `script::pace_frame`.
+    fn refresh_interval(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    // Time since the last input.
+    fn idle_time(&self) -> std::time::Duration;
+
+    fn reset_idle_time(&mut self);
+}
+
+// Backend without display, audio or input. Music is still mixed, so its
+// position and sync events advance as with audio.
+pub struct NullHost {
+    sound_channels: u8,
+    wants_quit: bool,
+    last_reset: std::time::Instant,
+}
+
+impl NullHost {
+    pub fn new(sound_channels: u8) -> Self {
+        Self {
+            sound_channels: sound_channels.max(1),
+            wants_quit: false,
+            last_reset: std::time::Instant::now(),
+        }
+    }
+}
+
+impl Default for NullHost {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl HostBackend for NullHost {
+    fn display_surface(&mut self, _rndr: &soft::State, _fb: u8, _skipped: &[(i16, i16)]) {}
+
+    fn sound_channel_count(&self) -> u8 {
+        self.sound_channels
+    }
+
+    fn play_sound(
+        &mut self,
+        _channel: u8,
+        _freq: u16,
+        _volume: u8,
+        _data: &[u8],
+        _len: usize,
+        _loops: i32,
+    ) {
+    }
+
+    fn stop_sound(&mut self, _channel: u8) {}
+
+    fn fade_out_sound(&mut self, _channel: u8, _ms: i32) {}
+
+    fn music_space(&self) -> Option<usize> {
+        None
+    }
+
+    fn queue_music(&mut self, _samples: &mut [i16]) {}
+
+    fn process_input(&mut self, _input: &mut script::Input, _photo_mode: bool) -> Vec<Action> {
+        Vec::new()
+    }
+
+    fn wants_quit(&self) -> bool {
+        self.wants_quit
+    }
+
+    fn request_quit(&mut self) {
+        self.wants_quit = true;
+    }
+
+    fn idle_time(&self) -> std::time::Duration {
+        self.last_reset.elapsed()
+    }
+
+    fn reset_idle_time(&mut self) {
+        self.last_reset = std::time::Instant::now();
+    }
+}
+
+// Draws overlays over given page and presents it.
+pub fn display_surface(g: &mut Game, fb: u8) {
+    let fb = if g.host.shows_grid() {
+        video::draw_grid(&mut g.video, fb)
+    } else {
+        fb
+    };
+    let fb = crate::captions::draw(g, fb);
+    let skipped = g.video.take_skipped_draws();
+    g.host.display_surface(&g.video.rndr, fb, &skipped);
+}
+
+// Remembers the currently displayed frame to crossfade from, when enabled.
+pub fn begin_scene_fade(g: &mut Game) {
+    g.host.begin_scene_fade(&g.video.rndr, g.video.front_page());
+}
+
+// F5 selects next running task, F6 freezes or unfreezes it, F7 freezes all
+// the others and F8 unfreezes everything.
+fn debug_tasks(g: &mut Game, key: TaskKey) {
+    if key == TaskKey::SelectNext {
+        let current = g.selected_task;
+        let active: Vec<usize> = g.vm.active_tasks().map(|(id, _)| id).collect();
+        g.selected_task = active
+            .iter()
+            .copied()
+            .find(|&id| current.is_none_or(|cur| id > cur))
+            .or_else(|| active.first().copied());
+        match g.selected_task {
+            Some(id) => log::info!("selected task {}", id),
+            None => log::info!("no running tasks"),
+        }
+        return;
+    }
+
+    if key == TaskKey::UnfreezeAll {
+        g.vm.unfreeze_all_tasks();
+        log::info!("unfroze all tasks");
+        return;
+    }
+
+    let id = match g.selected_task {
+        Some(id) => id,
+        None => {
+            log::info!("no task selected, press F5 first");
+            return;
+        }
+    };
+    if key == TaskKey::ToggleFrozen {
+        let frozen = !g.vm.is_task_frozen(id);
+        g.vm.set_task_frozen(id, frozen);
+        log::info!("task {} {}", id, if frozen { "frozen" } else { "unfrozen" });
+    } else if key == TaskKey::Solo {
+        g.vm.solo_task(id);
+        log::info!("task {} soloed", id);
+    }
+}
+
+// Fills free space of the audio buffer with music.
+pub fn produce_music(g: &mut Game) {
+    if g.music.is_end_of_track() {
+        return;
+    }
+    let space = match g.host.music_space() {
+        Some(space) => space,
+        // Nothing drains the buffer without audio, so it's mixed by ticks.
+        None => {
+            produce_music_ticks(g, 1);
+            return;
+        }
+    };
+
+    let mut buf = std::mem::take(&mut g.music_buf);
+    buf.resize(space, 0);
+    sfx::mix_samples(g, &mut buf);
+    g.host.queue_music(&mut buf);
+    g.music_buf = buf;
+}
+
+// Mixes exactly `ticks` 50 Hz ticks of music, regardless of how much fits
+// in the audio buffer.
+pub fn produce_music_ticks(g: &mut Game, ticks: u16) {
+    if g.music.is_end_of_track() {
+        return;
+    }
+
+    let mut buf = std::mem::take(&mut g.music_buf);
+    buf.resize(MUSIC_SAMPLES_PER_FRAME * usize::from(ticks), 0);
+    sfx::mix_samples(g, &mut buf);
+    g.host.queue_music(&mut buf);
+    g.music_buf = buf;
+}
+
+pub fn process_input(g: &mut Game) {
+    let photo_mode = photo::is_active(g);
+    for action in g.host.process_input(&mut g.input, photo_mode) {
+        match action {
+            Action::Photo(action) => photo::apply(g, action),
+            Action::Task(key) => debug_tasks(g, key),
+            Action::SkipScene => script::skip_scene(g),
+            Action::SlowMotion(true) => g.speed_multiplier = g.slow_motion_speed,
+            Action::SlowMotion(false) => g.speed_multiplier = 1.0,
+            Action::DumpFrame => {
+                let path = format!("frame{:06}.ppm", g.frame_count);
+                let fb = g.video.front_page();
+                match video::dump_ppm(g, fb, path.as_ref()) {
+                    Ok(()) => log::info!("saved {}", path),
+                    Err(e) => log::warn!("unable to write {}: {}", path, e),
+                }
+            }
+            Action::DumpPalette => {
+                let path = format!("palette{:06}.gpl", g.frame_count);
+                match video::write_palette(g, path.as_ref()) {
+                    Ok(()) => log::info!("saved {}", path),
+                    Err(e) => log::warn!("unable to write {}: {}", path, e),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,1030 @@
+use super::{Action, HostBackend, TaskKey, MUSIC_SAMPLES_PER_FRAME};
+use crate::video::soft::{self, PageSnapshot, PixelFormat, FB_SIZE, SCR_H, SCR_W};
+use crate::{photo, script, sfx};
+use sdl2::pixels::Color;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const MAX_SCALE: u32 = 8;
+
+// Number of frames for blending the last frame of a part into the next one.
+const SCENE_FADE_FRAMES: u8 = 12;
+
+const MUSIC_BUFFER_LEN: usize = MUSIC_SAMPLES_PER_FRAME * 8;
+
+// Set from signal handler on Ctrl+C, polled in `process_input`.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub struct Options {
+    pub fullscreen: bool,
+    pub pixel_format: PixelFormat,
+    // Mirror the presented image horizontally. Only the presentation is
+    // affected; the framebuffer and input (left stays left) are unchanged.
+    pub flip_h: bool,
+    // Wait for vertical blank when presenting. Game logic still runs at
+    // 50 Hz, see `script::pace_frame`.
+    pub vsync: bool,
+    // Initial window size as multiple of the game resolution, clamped to
+    // 1..=8, with height adjusted for pixel aspect. The window is 800x600
+    // when not set.
+    pub scale: Option<u32>,
+    // Crossfade from the last frame of a part instead of a hard cut. Only
+    // affects what is presented, never game logic or framebuffers.
+    pub scene_fade: bool,
+    // Edges of the game screen left out when presenting, the rest is scaled
+    // to the window.
+    pub crop: Crop,
+    pub pixel_aspect: PixelAspect,
+    // Number of sound effect channels, at least 1. Scripts use 4, more are
+    // only reachable by debug commands and mods.
+    pub sound_channels: u8,
+    // Open audio device. Audio is also disabled when the device or mixer is
+    // unavailable.
+    pub audio: bool,
+    // Initial master volume in percent and mute state, both can be changed
+    // with keys while playing.
+    pub volume: u8,
+    pub muted: bool,
+}
+
+// Shape of presented pixels. The original displays stretched 320x200 to 4:3,
+// so pixels were taller than wide. The image is letterboxed or pillarboxed
+// to keep the aspect within the window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelAspect {
+    Square,
+    FourThree,
+}
+
+impl PixelAspect {
+    // Height of a pixel relative to its width.
+    fn ratio(self) -> f32 {
+        match self {
+            PixelAspect::Square => 1.0,
+            PixelAspect::FourThree => (f32::from(SCR_W) * 3.0 / 4.0) / f32::from(SCR_H),
+        }
+    }
+
+    // Largest area of the window showing `src` with these pixels, centered.
+    pub fn dest_rect(self, src: sdl2::rect::Rect, window: (u32, u32)) -> sdl2::rect::Rect {
+        let (win_w, win_h) = (window.0 as f32, window.1 as f32);
+        let (src_w, src_h) = (src.width() as f32, src.height() as f32 * self.ratio());
+        let scale = f32::min(win_w / src_w, win_h / src_h);
+        let (w, h) = (
+            (src_w * scale).round() as u32,
+            (src_h * scale).round() as u32,
+        );
+        sdl2::rect::Rect::new(
+            (window.0.saturating_sub(w) / 2) as i32,
+            (window.1.saturating_sub(h) / 2) as i32,
+            w.max(1),
+            h.max(1),
+        )
+    }
+}
+
+impl std::str::FromStr for PixelAspect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "square" => Ok(PixelAspect::Square),
+            "4-3" => Ok(PixelAspect::FourThree),
+            _ => Err(format!("unknown pixel aspect {}", s)),
+        }
+    }
+}
+
+// Number of pixels cut from each edge of the game screen.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Crop {
+    pub top: u16,
+    pub bottom: u16,
+    pub left: u16,
+    pub right: u16,
+}
+
+impl Crop {
+    // Presented part of the game screen.
+    pub fn source_rect(&self) -> sdl2::rect::Rect {
+        sdl2::rect::Rect::new(
+            self.left.into(),
+            self.top.into(),
+            (SCR_W - self.left - self.right).into(),
+            (SCR_H - self.top - self.bottom).into(),
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        *self == Crop::default()
+    }
+}
+
+// Parses "TOP,BOTTOM,LEFT,RIGHT", which must leave some pixels visible.
+impl std::str::FromStr for Crop {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let edges = s
+            .split(',')
+            .map(|x| x.trim().parse::<u16>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| format!("invalid crop {}: {}", s, e))?;
+        let crop = match edges[..] {
+            [top, bottom, left, right] => Crop {
+                top,
+                bottom,
+                left,
+                right,
+            },
+            _ => return Err(format!("crop {} must have 4 values", s)),
+        };
+        let fits = |a: u16, b: u16, size: u16| u32::from(a) + u32::from(b) < u32::from(size);
+        if !fits(crop.top, crop.bottom, SCR_H) || !fits(crop.left, crop.right, SCR_W) {
+            return Err(format!("crop {} leaves nothing to display", s));
+        }
+        Ok(crop)
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            fullscreen: false,
+            pixel_format: PixelFormat::Rgb565,
+            flip_h: false,
+            vsync: false,
+            scale: None,
+            scene_fade: false,
+            crop: Crop::default(),
+            pixel_aspect: PixelAspect::FourThree,
+            sound_channels: 4,
+            audio: true,
+            volume: 100,
+            muted: false,
+        }
+    }
+}
+
+pub struct SdlHost {
+    #[allow(dead_code)]
+    sdl_context: sdl2::Sdl,
+    #[allow(dead_code)]
+    video_subsystem: sdl2::VideoSubsystem,
+    surface: sdl2::render::Texture,
+    pixel_format: PixelFormat,
+    // Palettized copy of the displayed page and surface it's converted to
+    // by SDL, for indexed output.
+    index_surfaces: Option<(
+        sdl2::surface::Surface<'static>,
+        sdl2::surface::Surface<'static>,
+    )>,
+    flip_h: bool,
+    crop: Crop,
+    pixel_aspect: PixelAspect,
+    color_buffer: Vec<u16>,
+    argb_buffer: Vec<u32>,
+    canvas: sdl2::render::Canvas<sdl2::video::Window>,
+    event_pump: sdl2::EventPump,
+
+    #[allow(dead_code)]
+    mixer_context: Option<sdl2::mixer::Sdl2MixerContext>,
+    // Whether sounds and music are played. Music is still mixed without
+    // audio, so its position and sync events advance as usual.
+    audio: bool,
+    // Master volume in percent, applied on top of volumes set by the game.
+    volume: u8,
+    muted: bool,
+    audio_cvt: sdl2::audio::AudioCVT,
+    audio_channels: Vec<AudioChannel<u8>>,
+    music_chan: rb::SpscRb<i16>,
+    music_chan_prod: rb::Producer<i16>,
+    // Read by `consume_music` on the audio thread, freed on drop.
+    music_chan_cons: Option<*mut rb::Consumer<i16>>,
+    on_present: Option<PresentHook>,
+    scene_fade: bool,
+    // Previous part's front page and number of frames left to blend it.
+    fade: Option<(PageSnapshot, u8)>,
+    wants_quit: bool,
+    wants_pause: bool,
+    show_grid: bool,
+    // Run single frame while paused.
+    wants_step: bool,
+    last_input_time: std::time::Instant,
+    // Set when presenting waits for vertical blank.
+    refresh_interval: Option<std::time::Duration>,
+}
+
+// Called with every presented frame: row-major RGB565 pixels and their row
+// width. The frame is 320x200 unless cropped.
+pub type PresentHook = Box<dyn FnMut(&[u16], usize)>;
+
+#[derive(Default)]
+struct AudioChannel<T> {
+    chunk: Option<sdl2::mixer::Chunk>,
+    samples: Vec<T>,
+    // Volume requested by the game, 0..=63.
+    volume: u8,
+}
+
+fn as_u8_slice<T>(v: &[T]) -> &[u8] {
+    unsafe { std::slice::from_raw_parts(v.as_ptr() as *const u8, std::mem::size_of_val(v)) }
+}
+
+// Errors are logged and the frame is dropped, as they are usually transient.
+fn display_surface(h: &mut SdlHost, rndr: &soft::State, fb: u8, skipped: &[(i16, i16)]) {
+    let fade = h.fade.as_mut().map(|(from, left)| {
+        *left -= 1;
+        let weight = u16::from(*left) * 255 / u16::from(SCENE_FADE_FRAMES);
+        (&*from, weight as u8)
+    });
+    if h.pixel_format == PixelFormat::Rgb565 || h.on_present.is_some() {
+        match fade {
+            Some((from, weight)) => rndr.read_pixels_blended(fb, from, weight, &mut h.color_buffer),
+            None => rndr.read_pixels(fb, &mut h.color_buffer),
+        }
+    }
+    if h.pixel_format == PixelFormat::Argb8888 {
+        match fade {
+            Some((from, weight)) => {
+                rndr.read_argb8888_blended(fb, from, weight, &mut h.argb_buffer)
+            }
+            None => rndr.read_argb8888(fb, &mut h.argb_buffer),
+        }
+    }
+    if let Some((_, 0)) = h.fade {
+        h.fade = None;
+    }
+    if let Some(on_present) = &mut h.on_present {
+        if h.crop.is_empty() {
+            on_present(&h.color_buffer, usize::from(SCR_W));
+        } else {
+            let rect = h.crop.source_rect();
+            let pixels: Vec<u16> = h
+                .color_buffer
+                .chunks(usize::from(SCR_W))
+                .skip(rect.y() as usize)
+                .take(rect.height() as usize)
+                .flat_map(|row| &row[rect.x() as usize..rect.right() as usize])
+                .copied()
+                .collect();
+            on_present(&pixels, rect.width() as usize);
+        }
+    }
+    let result = match h.pixel_format {
+        PixelFormat::Rgb565 => {
+            let pixels = as_u8_slice(&h.color_buffer);
+            h.surface
+                .update(None, pixels, usize::from(SCR_W) * 2)
+                .map_err(|e| e.to_string())
+        }
+        PixelFormat::Argb8888 => {
+            let pixels = as_u8_slice(&h.argb_buffer);
+            h.surface
+                .update(None, pixels, usize::from(SCR_W) * 4)
+                .map_err(|e| e.to_string())
+        }
+        PixelFormat::Indexed8 => upload_indexed(h, rndr, fb),
+    };
+    if let Err(e) = result {
+        log::error!("unable to update texture: {}", e);
+        return;
+    }
+    let flip_h = h.flip_h;
+    let src = h.crop.source_rect();
+    let dst = match h.canvas.output_size() {
+        Ok(size) => h.pixel_aspect.dest_rect(src, size),
+        Err(e) => {
+            log::error!("unable to get output size: {}", e);
+            return;
+        }
+    };
+    h.canvas.set_draw_color(Color::RGB(0, 0, 0));
+    h.canvas.clear();
+    if let Err(e) = h
+        .canvas
+        .copy_ex(&h.surface, src, dst, 0.0, None, flip_h, false)
+    {
+        log::error!("unable to copy texture to canvas: {}", e);
+        return;
+    }
+    draw_skip_markers(h, dst, skipped);
+    h.canvas.present();
+}
+
+// Scene fade isn't applied here, as blended colors aren't in the palette.
+fn upload_indexed(h: &mut SdlHost, rndr: &soft::State, fb: u8) -> Result<(), String> {
+    let (surf, converted) = match &mut h.index_surfaces {
+        Some((surf, converted)) => (surf, converted),
+        None => return Err("no indexed surface".to_owned()),
+    };
+
+    let colors: Vec<_> = rndr
+        .display_palette()
+        .iter()
+        .map(|c| Color::RGB(c.r, c.g, c.b))
+        .collect();
+    surf.set_palette(&sdl2::pixels::Palette::with_colors(&colors)?)?;
+
+    let pitch = surf.pitch() as usize;
+    let page = rndr.page(fb);
+    surf.with_lock_mut(|pixels| {
+        for (dst, src) in pixels
+            .chunks_mut(pitch)
+            .zip(page.chunks(usize::from(SCR_W)))
+        {
+            dst[..src.len()].copy_from_slice(src);
+        }
+    });
+
+    surf.blit(None, converted, None)?;
+    let pitch = converted.pitch() as usize;
+    let texture = &mut h.surface;
+    converted
+        .with_lock(|pixels| texture.update(None, pixels, pitch))
+        .map_err(|e| e.to_string())
+}
+
+// Marks shapes skipped in strict video mode with magenta boxes. Drawn on the
+// canvas, so game pages stay untouched.
+fn draw_skip_markers(h: &mut SdlHost, dst: sdl2::rect::Rect, markers: &[(i16, i16)]) {
+    if markers.is_empty() {
+        return;
+    }
+    let src = h.crop.source_rect();
+    let scale_x = dst.width() as f32 / src.width() as f32;
+    let scale_y = dst.height() as f32 / src.height() as f32;
+    h.canvas.set_draw_color(Color::RGB(255, 0, 255));
+    for &(x, y) in markers {
+        let x = if h.flip_h {
+            src.right() as f32 - f32::from(x)
+        } else {
+            f32::from(x) - src.x() as f32
+        };
+        let y = f32::from(y) - src.y() as f32;
+        let rect = sdl2::rect::Rect::new(
+            dst.x() + ((x - 4.0) * scale_x) as i32,
+            dst.y() + ((y - 4.0) * scale_y) as i32,
+            (8.0 * scale_x) as u32,
+            (8.0 * scale_y) as u32,
+        );
+        if let Err(e) = h.canvas.draw_rect(rect) {
+            log::error!("unable to draw marker: {}", e);
+        }
+    }
+}
+
+// Remembers the currently displayed frame to crossfade from, when enabled.
+fn begin_scene_fade(h: &mut SdlHost, rndr: &soft::State, fb: u8) {
+    if h.scene_fade {
+        h.fade = Some((rndr.snapshot(fb), SCENE_FADE_FRAMES));
+    }
+}
+
+impl SdlHost {
+    pub fn new(opts: &Options) -> Self {
+        use rb::RB;
+
+        let sdl_context = sdl2::init().unwrap();
+        let video_subsystem = sdl_context.video().unwrap();
+
+        let (width, height) = match opts.scale {
+            Some(n) => {
+                let n = n.clamp(1, MAX_SCALE);
+                let height = f32::from(SCR_H) * opts.pixel_aspect.ratio();
+                (u32::from(SCR_W) * n, height as u32 * n)
+            }
+            None => (800, 600),
+        };
+        let mut window = video_subsystem.window("Out Of Rust World", width, height);
+
+        if opts.fullscreen {
+            window.fullscreen();
+        } else {
+            window.position_centered();
+        }
+
+        let window = window.build().unwrap();
+
+        let mut canvas = window.into_canvas();
+        if opts.vsync {
+            canvas = canvas.present_vsync();
+        }
+        let mut canvas = canvas.build().unwrap();
+        let refresh_interval = if opts.vsync {
+            let mode = canvas.window().display_mode();
+            match mode {
+                Ok(mode) if mode.refresh_rate > 0 => {
+                    Some(std::time::Duration::from_secs(1) / mode.refresh_rate as u32)
+                }
+                _ => {
+                    log::warn!("unknown display refresh rate, pacing as without vsync");
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let pixel_format = choose_pixel_format(&canvas.info(), opts.pixel_format);
+        let texture_creator = canvas.texture_creator();
+        let surface = texture_creator
+            .create_texture_streaming(sdl_pixel_format(pixel_format), SCR_W.into(), SCR_H.into())
+            .unwrap();
+        let index_surfaces = if pixel_format == PixelFormat::Indexed8 {
+            let new_surface =
+                |format| sdl2::surface::Surface::new(SCR_W.into(), SCR_H.into(), format).unwrap();
+            Some((
+                new_surface(sdl2::pixels::PixelFormatEnum::Index8),
+                new_surface(sdl_pixel_format(pixel_format)),
+            ))
+        } else {
+            None
+        };
+
+        canvas.set_draw_color(Color::RGB(0, 255, 255));
+        canvas.clear();
+        canvas.present();
+
+        let event_pump = sdl_context.event_pump().unwrap();
+
+        // Installed after SDL, so it replaces SDL's own handler.
+        install_interrupt_handler();
+
+        use sdl2::audio::AudioFormat;
+        let audio_cvt = sdl2::audio::AudioCVT::new(
+            AudioFormat::S8,
+            1,
+            sfx::GAME_RATE.into(),
+            AudioFormat::s16_sys(),
+            2,
+            sfx::HOST_RATE.into(),
+        )
+        .unwrap();
+
+        let sound_channels = opts.sound_channels.max(1);
+        let mixer_context = if opts.audio {
+            match init_mixer(sound_channels) {
+                Ok(context) => Some(context),
+                Err(e) => {
+                    log::warn!("audio disabled: {}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let audio = mixer_context.is_some();
+
+        let music_chan = rb::SpscRb::new(MUSIC_BUFFER_LEN);
+        let (music_chan_prod, music_chan_cons) = (music_chan.producer(), music_chan.consumer());
+
+        let music_chan_cons = if audio {
+            let cons = Box::into_raw(Box::new(music_chan_cons));
+            unsafe {
+                sdl2::sys::mixer::Mix_HookMusic(Some(consume_music), cons as *mut libc::c_void);
+            }
+            Some(cons)
+        } else {
+            None
+        };
+
+        Self {
+            sdl_context,
+            video_subsystem,
+            canvas,
+            surface,
+            pixel_format,
+            index_surfaces,
+            flip_h: opts.flip_h,
+            crop: opts.crop,
+            pixel_aspect: opts.pixel_aspect,
+            color_buffer: vec![0; FB_SIZE],
+            argb_buffer: vec![0; FB_SIZE],
+            mixer_context,
+            audio,
+            volume: std::cmp::min(opts.volume, 100),
+            muted: opts.muted,
+            audio_channels: (0..sound_channels).map(|_| Default::default()).collect(),
+            audio_cvt,
+            music_chan,
+            music_chan_prod,
+            music_chan_cons,
+            event_pump,
+            on_present: None,
+            scene_fade: opts.scene_fade,
+            fade: None,
+            wants_quit: false,
+            wants_pause: false,
+            show_grid: false,
+            wants_step: false,
+            last_input_time: std::time::Instant::now(),
+            refresh_interval,
+        }
+    }
+
+    // Master volume as a fraction.
+    fn master_gain(&self) -> (i32, i32) {
+        if self.muted {
+            (0, 100)
+        } else {
+            (self.volume.into(), 100)
+        }
+    }
+
+    pub fn set_on_present(&mut self, hook: Option<PresentHook>) {
+        self.on_present = hook;
+    }
+}
+
+impl HostBackend for SdlHost {
+    fn display_surface(&mut self, rndr: &soft::State, fb: u8, skipped: &[(i16, i16)]) {
+        display_surface(self, rndr, fb, skipped);
+    }
+
+    fn begin_scene_fade(&mut self, rndr: &soft::State, fb: u8) {
+        begin_scene_fade(self, rndr, fb);
+    }
+
+    fn shows_grid(&self) -> bool {
+        self.show_grid
+    }
+
+    fn sound_channel_count(&self) -> u8 {
+        self.audio_channels.len() as u8
+    }
+
+    fn play_sound(
+        &mut self,
+        channel: u8,
+        freq: u16,
+        volume: u8,
+        data: &[u8],
+        len: usize,
+        loops: i32,
+    ) {
+        play_sound(self, channel, freq, volume, data, len, loops);
+    }
+
+    fn stop_sound(&mut self, channel: u8) {
+        stop_sound(self, channel);
+    }
+
+    fn fade_out_sound(&mut self, channel: u8, ms: i32) {
+        fade_out_sound(self, channel, ms);
+    }
+
+    fn music_space(&self) -> Option<usize> {
+        use rb::RbInspector;
+
+        // Nothing drains the buffer without audio.
+        if self.audio {
+            Some(self.music_chan.slots_free())
+        } else {
+            None
+        }
+    }
+
+    fn queue_music(&mut self, samples: &mut [i16]) {
+        queue_music(self, samples);
+    }
+
+    fn process_input(&mut self, input: &mut script::Input, photo_mode: bool) -> Vec<Action> {
+        process_input(self, input, photo_mode)
+    }
+
+    fn wants_quit(&self) -> bool {
+        self.wants_quit
+    }
+
+    fn request_quit(&mut self) {
+        self.wants_quit = true;
+    }
+
+    fn wants_pause(&self) -> bool {
+        self.wants_pause
+    }
+
+    fn take_step(&mut self) -> bool {
+        std::mem::replace(&mut self.wants_step, false)
+    }
+
+    fn idle_time(&self) -> std::time::Duration {
+        self.last_input_time.elapsed()
+    }
+
+    fn reset_idle_time(&mut self) {
+        self.last_input_time = std::time::Instant::now();
+    }
+
+    fn refresh_interval(&self) -> Option<std::time::Duration> {
+        self.refresh_interval
+    }
+}
+
+fn sdl_pixel_format(format: PixelFormat) -> sdl2::pixels::PixelFormatEnum {
+    match format {
+        PixelFormat::Rgb565 => sdl2::pixels::PixelFormatEnum::RGB565,
+        PixelFormat::Argb8888 | PixelFormat::Indexed8 => sdl2::pixels::PixelFormatEnum::ARGB8888,
+    }
+}
+
+// Falls back to the other format if renderer doesn't support requested one.
+fn choose_pixel_format(info: &sdl2::render::RendererInfo, wanted: PixelFormat) -> PixelFormat {
+    let supported = |f| info.texture_formats.contains(&sdl_pixel_format(f));
+    if supported(wanted) || info.texture_formats.is_empty() {
+        return wanted;
+    }
+    let fallback = match wanted {
+        PixelFormat::Rgb565 => PixelFormat::Argb8888,
+        PixelFormat::Argb8888 | PixelFormat::Indexed8 => PixelFormat::Rgb565,
+    };
+    if supported(fallback) {
+        log::warn!(
+            "{:?} not supported by {} renderer, using {:?}",
+            wanted,
+            info.name,
+            fallback
+        );
+        fallback
+    } else {
+        wanted
+    }
+}
+
+#[cfg(unix)]
+fn install_interrupt_handler() {
+    extern "C" fn handler(_signal: libc::c_int) {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+    }
+
+    unsafe {
+        libc::signal(libc::SIGINT, handler as *const () as libc::sighandler_t);
+    }
+}
+
+#[cfg(windows)]
+fn install_interrupt_handler() {
+    type HandlerRoutine = unsafe extern "system" fn(u32) -> i32;
+
+    extern "system" {
+        fn SetConsoleCtrlHandler(handler: Option<HandlerRoutine>, add: i32) -> i32;
+    }
+
+    unsafe extern "system" fn handler(_ctrl_type: u32) -> i32 {
+        INTERRUPTED.store(true, Ordering::SeqCst);
+        1
+    }
+
+    unsafe {
+        SetConsoleCtrlHandler(Some(handler), 1);
+    }
+}
+
+// The mixer keeps calling the music hook and playing chunks on the audio
+// thread until audio is closed, so that's done before the consumer and
+// sample buffers they read are freed with the rest of `SdlHost`.
+impl Drop for SdlHost {
+    fn drop(&mut self) {
+        if !self.audio {
+            return;
+        }
+        // Replacing the hook locks the audio thread, so the old one isn't
+        // running anymore when this returns.
+        unsafe {
+            sdl2::sys::mixer::Mix_HookMusic(None, std::ptr::null_mut());
+        }
+        sdl2::mixer::Channel::all().halt();
+        for ac in &mut self.audio_channels {
+            ac.chunk = None;
+        }
+        sdl2::mixer::close_audio();
+        if let Some(cons) = self.music_chan_cons.take() {
+            drop(unsafe { Box::from_raw(cons) });
+        }
+    }
+}
+
+fn init_mixer(channels: u8) -> Result<sdl2::mixer::Sdl2MixerContext, String> {
+    let ret = unsafe { sdl2::sys::mixer::Mix_Init(0) };
+    if ret != 0 {
+        return Err(format!("unable to initialize mixer ({})", ret));
+    }
+    let context = sdl2::mixer::Sdl2MixerContext;
+    sdl2::mixer::open_audio(sfx::HOST_RATE.into(), sdl2::mixer::AUDIO_S16SYS, 2, 4096)?;
+    sdl2::mixer::allocate_channels(channels.into());
+    Ok(context)
+}
+
+fn play_sound(
+    h: &mut SdlHost,
+    channel: u8,
+    freq: u16,
+    volume: u8,
+    data: &[u8],
+    len: usize,
+    loops: i32,
+) {
+    let freq = clamp_sound_freq(freq);
+    stop_sound(h, channel);
+    if !h.audio {
+        return;
+    }
+
+    let ac = &mut h.audio_channels[usize::from(channel)];
+    ac.samples.resize(h.audio_cvt.capacity(len * 4), 0);
+
+    let mut pos = sfx::Frac::new(freq, sfx::GAME_RATE);
+    let mut n = 0;
+    while pos.int() < (len as u32) {
+        ac.samples[n] = data[pos.int() as usize];
+        n += 1;
+        pos.inc();
+    }
+    ac.samples.truncate(n);
+    ac.samples = h.audio_cvt.convert(std::mem::take(&mut ac.samples));
+
+    ac.chunk = Some({
+        let raw_chunk = unsafe {
+            sdl2::sys::mixer::Mix_QuickLoad_RAW(ac.samples.as_mut_ptr(), ac.samples.len() as u32)
+        };
+        sdl2::mixer::Chunk {
+            raw: raw_chunk,
+            owned: true,
+        }
+    });
+
+    ac.volume = volume;
+    let mixer_channel = sdl2::mixer::Channel(channel.into());
+    mixer_channel
+        .play(ac.chunk.as_ref().unwrap(), loops)
+        .unwrap();
+    apply_channel_volume(h, channel);
+}
+
+// Sample buffer is sized for at most 4x oversampling of the input, the
+// minimum is rounded up so resampling never produces more.
+fn clamp_sound_freq(freq: u16) -> u16 {
+    const MIN_FREQ: u16 = sfx::GAME_RATE.div_ceil(4);
+    if freq < MIN_FREQ {
+        log::warn!("sound frequency {} too low, using {}", freq, MIN_FREQ);
+        MIN_FREQ
+    } else {
+        freq
+    }
+}
+
+fn apply_channel_volume(h: &SdlHost, channel: u8) {
+    let volume = i32::from(h.audio_channels[usize::from(channel)].volume);
+    let gain = h.master_gain();
+    sdl2::mixer::Channel(channel.into())
+        .set_volume((volume * sdl2::mixer::MAX_VOLUME / 63) * gain.0 / gain.1);
+}
+
+// Changes master volume by given percentage points, or toggles mute.
+fn change_volume(h: &mut SdlHost, delta: i16, toggle_mute: bool) {
+    if toggle_mute {
+        h.muted = !h.muted;
+    }
+    h.volume = (i16::from(h.volume) + delta).clamp(0, 100) as u8;
+    if h.muted {
+        log::info!("muted");
+    } else {
+        log::info!("volume {}%", h.volume);
+    }
+    if h.audio {
+        for channel in 0..h.sound_channel_count() {
+            apply_channel_volume(h, channel);
+        }
+    }
+}
+
+// Scales music samples by master volume.
+fn apply_music_volume(h: &SdlHost, samples: &mut [i16]) {
+    let (num, den) = h.master_gain();
+    if num != den {
+        for sample in samples {
+            *sample = (i32::from(*sample) * num / den) as i16;
+        }
+    }
+}
+
+fn stop_sound(h: &mut SdlHost, channel: u8) {
+    if h.audio {
+        sdl2::mixer::Channel(channel.into()).halt();
+    }
+    h.audio_channels[usize::from(channel)].chunk = None;
+}
+
+// The chunk is kept alive until the channel is reused or stopped, as the
+// mixer still plays it while fading.
+fn fade_out_sound(h: &mut SdlHost, channel: u8, ms: i32) {
+    if h.audio {
+        sdl2::mixer::Channel(channel.into()).fade_out(ms);
+    }
+}
+
+// Samples not fitting in the ring buffer are dropped, so music position
+// doesn't depend on the device.
+fn queue_music(h: &mut SdlHost, samples: &mut [i16]) {
+    use rb::RbProducer;
+
+    apply_music_volume(h, samples);
+    let _ = h.music_chan_prod.write(samples);
+}
+
+#[allow(clippy::cast_ptr_alignment)]
+unsafe extern "C" fn consume_music(udata: *mut libc::c_void, stream: *mut u8, len: libc::c_int) {
+    use rb::RbConsumer;
+    let music_chan_cons = (udata as *mut rb::Consumer<i16>).as_ref().unwrap();
+    let out = std::slice::from_raw_parts_mut(stream as *mut i16, (len as usize) / 2);
+    let count = music_chan_cons.read(out).unwrap_or(0);
+    for sample in &mut out[count..] {
+        *sample = 0;
+    }
+}
+
+// Any of these resets the idle time, not only keys the game reacts to.
+fn is_user_input(event: &sdl2::event::Event) -> bool {
+    use sdl2::event::Event;
+
+    matches!(
+        event,
+        Event::KeyDown { .. }
+            | Event::KeyUp { .. }
+            | Event::TextInput { .. }
+            | Event::MouseMotion { .. }
+            | Event::MouseButtonDown { .. }
+            | Event::MouseButtonUp { .. }
+            | Event::MouseWheel { .. }
+            | Event::JoyAxisMotion { .. }
+            | Event::JoyBallMotion { .. }
+            | Event::JoyHatMotion { .. }
+            | Event::JoyButtonDown { .. }
+            | Event::JoyButtonUp { .. }
+            | Event::ControllerAxisMotion { .. }
+            | Event::ControllerButtonDown { .. }
+            | Event::ControllerButtonUp { .. }
+            | Event::FingerDown { .. }
+            | Event::FingerUp { .. }
+            | Event::FingerMotion { .. }
+    )
+}
+
+fn process_input(h: &mut SdlHost, input: &mut script::Input, in_photo_mode: bool) -> Vec<Action> {
+    use sdl2::event::Event;
+    use sdl2::keyboard::{Keycode, Mod};
+    use std::convert::TryFrom;
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        h.wants_quit = true;
+    }
+
+    let mut actions = Vec::new();
+    // Applied after polling, as `h` is borrowed meanwhile.
+    let mut volume_change = None;
+
+    for event in h.event_pump.poll_iter() {
+        if is_user_input(&event) {
+            h.last_input_time = std::time::Instant::now();
+        }
+        match event {
+            Event::Quit { .. }
+            | Event::KeyDown {
+                keycode: Some(Keycode::Escape),
+                ..
+            } => h.wants_quit = true,
+
+            Event::KeyDown {
+                keycode: Some(k), ..
+            } if in_photo_mode => {
+                use crate::photo::Action as Photo;
+                let action = match k {
+                    Keycode::F3 => Photo::Toggle,
+                    Keycode::Left => Photo::PrevPage,
+                    Keycode::Right => Photo::NextPage,
+                    Keycode::LeftBracket => Photo::PrevPal,
+                    Keycode::RightBracket => Photo::NextPal,
+                    Keycode::F12 => Photo::Screenshot,
+                    _ => continue,
+                };
+                actions.push(Action::Photo(action));
+            }
+
+            Event::KeyDown {
+                keycode: Some(k),
+                keymod,
+                ..
+            } => {
+                let shift = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                match k {
+                    Keycode::Left => input.left = true,
+                    Keycode::Right => input.right = true,
+                    Keycode::Up => input.up = true,
+                    Keycode::Down => input.down = true,
+                    Keycode::Space | Keycode::Return => input.button = true,
+                    Keycode::P => h.wants_pause = !h.wants_pause,
+                    Keycode::F2 => h.show_grid = !h.show_grid,
+                    Keycode::F3 => actions.push(Action::Photo(photo::Action::Toggle)),
+                    Keycode::F5 => actions.push(Action::Task(TaskKey::SelectNext)),
+                    Keycode::F6 => actions.push(Action::Task(TaskKey::ToggleFrozen)),
+                    Keycode::F7 => actions.push(Action::Task(TaskKey::Solo)),
+                    Keycode::F8 => actions.push(Action::Task(TaskKey::UnfreezeAll)),
+                    Keycode::F9 => actions.push(Action::DumpFrame),
+                    Keycode::F11 => actions.push(Action::DumpPalette),
+                    Keycode::Greater => actions.push(Action::SkipScene),
+                    Keycode::Period if shift => actions.push(Action::SkipScene),
+                    Keycode::Period if h.wants_pause => h.wants_step = true,
+                    Keycode::Backquote => actions.push(Action::SlowMotion(true)),
+                    Keycode::Minus => volume_change = Some((-10, false)),
+                    Keycode::Equals => volume_change = Some((10, false)),
+                    Keycode::F10 => volume_change = Some((0, true)),
+                    _ => {}
+                }
+                input.last_char = u8::try_from(k as i32).ok();
+            }
+
+            Event::KeyUp {
+                keycode: Some(k), ..
+            } => match k {
+                Keycode::Left => input.left = false,
+                Keycode::Right => input.right = false,
+                Keycode::Up => input.up = false,
+                Keycode::Down => input.down = false,
+                Keycode::Space | Keycode::Return => input.button = false,
+                Keycode::Backquote => actions.push(Action::SlowMotion(false)),
+                _ => {}
+            },
+
+            _ => {}
+        }
+    }
+
+    if let Some((delta, toggle_mute)) = volume_change {
+        change_volume(h, delta, toggle_mute);
+    }
+
+    actions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::rect::Rect;
+
+    // Runs with dummy drivers, but still needs the SDL2 libraries at run
+    // time, unlike the other tests.
+    #[test]
+    #[ignore = "needs SDL2 and SDL2_mixer"]
+    fn host_with_audio_drops_cleanly() {
+        std::env::set_var("SDL_VIDEODRIVER", "dummy");
+        std::env::set_var("SDL_AUDIODRIVER", "dummy");
+        // The second host opens audio again, after the first one closed it.
+        for _ in 0..2 {
+            let mut host = SdlHost::new(&Options::default());
+            assert!(host.audio);
+            host.queue_music(&mut [0; 64]);
+            drop(host);
+        }
+    }
+
+    #[test]
+    fn low_sound_freq_is_clamped() {
+        assert_eq!(clamp_sound_freq(0), 2757);
+        assert_eq!(clamp_sound_freq(1), 2757);
+        assert_eq!(clamp_sound_freq(8000), 8000);
+
+        // Resampling at the clamped frequency stays within the budget.
+        let len = 100;
+        let mut pos = sfx::Frac::new(clamp_sound_freq(1), sfx::GAME_RATE);
+        let mut n = 0;
+        while pos.int() < len {
+            n += 1;
+            pos.inc();
+        }
+        assert!(n <= len * 4);
+    }
+
+    #[test]
+    fn dest_rect_keeps_pixel_aspect() {
+        let src = Rect::new(0, 0, SCR_W.into(), SCR_H.into());
+
+        let four_three = PixelAspect::FourThree;
+        assert_eq!(
+            four_three.dest_rect(src, (1280, 960)),
+            Rect::new(0, 0, 1280, 960)
+        );
+        assert_eq!(
+            four_three.dest_rect(src, (1920, 1080)),
+            Rect::new(240, 0, 1440, 1080)
+        );
+
+        let square = PixelAspect::Square;
+        assert_eq!(
+            square.dest_rect(src, (1280, 960)),
+            Rect::new(0, 80, 1280, 800)
+        );
+        assert_eq!(
+            square.dest_rect(src, (1920, 1080)),
+            Rect::new(96, 0, 1728, 1080)
+        );
+    }
+}
@@ -0,0 +1,174 @@
+// Input recorded for every frame, played back with `Game::advance`.
+//
+// Saved format, all integers little endian:
+//
+//   "OILG"
+//   u32 number of frames
+//   u32 number of runs, each u32 length and u8 keys (right, left, down, up
+//       and button from bit 0) of consecutive frames with equal keys
+//   u32 number of characters, each u32 frame and u8 `last_char`
+//
+// Held keys change rarely, so they are run-length encoded. Characters are
+// only typed on the password screen and are stored separately.
+
+use crate::script::InputState;
+use byteorder::{ReadBytesExt, WriteBytesExt, LE};
+use std::io::{self, Read, Write};
+
+const MAGIC: &[u8; 4] = b"OILG";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct InputLog {
+    frames: Vec<InputState>,
+}
+
+impl InputLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, state: InputState) {
+        self.frames.push(state);
+    }
+
+    pub fn frames(&self) -> &[InputState] {
+        &self.frames
+    }
+
+    pub fn save(&self, w: &mut impl Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u32::<LE>(self.frames.len() as u32)?;
+
+        let mut runs: Vec<(u32, u8)> = Vec::new();
+        for keys in self.frames.iter().map(keys_of) {
+            match runs.last_mut() {
+                Some((len, k)) if *k == keys => *len += 1,
+                _ => runs.push((1, keys)),
+            }
+        }
+        w.write_u32::<LE>(runs.len() as u32)?;
+        for (len, keys) in runs {
+            w.write_u32::<LE>(len)?;
+            w.write_u8(keys)?;
+        }
+
+        let chars: Vec<_> = self
+            .frames
+            .iter()
+            .enumerate()
+            .filter_map(|(i, s)| s.last_char.map(|c| (i as u32, c)))
+            .collect();
+        w.write_u32::<LE>(chars.len() as u32)?;
+        for (frame, c) in chars {
+            w.write_u32::<LE>(frame)?;
+            w.write_u8(c)?;
+        }
+        Ok(())
+    }
+
+    pub fn load(r: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not an input log"));
+        }
+        let count = r.read_u32::<LE>()? as usize;
+
+        let mut frames = Vec::new();
+        for _ in 0..r.read_u32::<LE>()? {
+            let len = r.read_u32::<LE>()? as usize;
+            let state = state_of(r.read_u8()?);
+            if frames.len() + len > count {
+                return Err(invalid_data("input runs exceed frame count"));
+            }
+            frames.extend(std::iter::repeat_n(state, len));
+        }
+        if frames.len() != count {
+            return Err(invalid_data("input runs don't cover all frames"));
+        }
+
+        for _ in 0..r.read_u32::<LE>()? {
+            let frame = r.read_u32::<LE>()? as usize;
+            let c = r.read_u8()?;
+            match frames.get_mut(frame) {
+                Some(state) => state.last_char = Some(c),
+                None => return Err(invalid_data("character past the last frame")),
+            }
+        }
+        Ok(Self { frames })
+    }
+}
+
+fn keys_of(s: &InputState) -> u8 {
+    u8::from(s.right)
+        | (u8::from(s.left) << 1)
+        | (u8::from(s.down) << 2)
+        | (u8::from(s.up) << 3)
+        | (u8::from(s.button) << 4)
+}
+
+fn state_of(keys: u8) -> InputState {
+    InputState {
+        last_char: None,
+        right: (keys & 1) != 0,
+        left: (keys & 2) != 0,
+        down: (keys & 4) != 0,
+        up: (keys & 8) != 0,
+        button: (keys & 16) != 0,
+    }
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip_keeps_every_frame() {
+        let idle = InputState::default();
+        let running = InputState {
+            right: true,
+            ..idle
+        };
+        let mut log = InputLog::new();
+        for _ in 0..10_000 {
+            log.push(idle);
+        }
+        for _ in 0..500 {
+            log.push(running);
+        }
+        log.push(InputState {
+            last_char: Some(b'k'),
+            button: true,
+            ..idle
+        });
+        for _ in 0..10_000 {
+            log.push(idle);
+        }
+
+        let mut saved = Vec::new();
+        log.save(&mut saved).unwrap();
+        // Magic, counts, 4 runs and a character.
+        assert_eq!(saved.len(), 4 + 4 + 4 + 4 * 5 + 4 + 5);
+
+        let loaded = InputLog::load(&mut &saved[..]).unwrap();
+        assert_eq!(loaded, log);
+    }
+
+    #[test]
+    fn load_rejects_runs_past_frame_count() {
+        let mut saved = Vec::new();
+        saved.extend_from_slice(MAGIC);
+        saved.extend_from_slice(&2u32.to_le_bytes());
+        saved.extend_from_slice(&1u32.to_le_bytes());
+        saved.extend_from_slice(&3u32.to_le_bytes());
+        saved.push(0);
+        saved.extend_from_slice(&0u32.to_le_bytes());
+
+        let err = InputLog::load(&mut &saved[..]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,336 @@
+// Another World interpreter. The `oorw` binary is a command line front-end
+// for it, other programs can create a `Game` and drive `run_frame` the same
+// way, with `host::NullHost` when there's no display or audio device.
+
+pub mod bytekiller;
+pub mod captions;
+#[cfg(all(unix, feature = "control-socket"))]
+pub mod control;
+pub mod data;
+pub mod debug;
+pub mod host;
+pub mod input_log;
+pub mod lockstep;
+pub mod mem;
+pub mod pak;
+pub mod photo;
+mod png;
+pub mod script;
+pub mod sfx;
+pub mod source;
+#[cfg(test)]
+mod testing;
+pub mod video;
+pub mod watch;
+
+pub use host::{HostBackend, NullHost, SdlHost};
+pub use mem::Memory;
+pub use script::{restart_at, Vm};
+pub use video::VideoContext;
+
+// FIXME: ability to resize a window during gameplay
+
+pub struct Game {
+    pub mem: Memory,
+    pub vm: Vm,
+    pub video: VideoContext,
+    current_part: u16,
+    next_part: Option<u16>,
+    screen_num: Option<i16>,
+    next_pal: Option<u8>,
+    looping_gun_quirk: bool,
+    bypass_protection: bool,
+
+    pub music: sfx::Player,
+    // Scratch buffer for mixing music, kept to avoid allocating every frame.
+    music_buf: Vec<i16>,
+    pub host: Box<dyn HostBackend>,
+    input: script::Input,
+    // Task selected for freezing with debug keys.
+    selected_task: Option<usize>,
+
+    photo: Option<photo::PhotoMode>,
+    pub captions: Option<captions::Captions>,
+
+    frame_count: u64,
+    start_time: std::time::Instant,
+    pub debug: Option<Box<debug::DebugState>>,
+    // Restart the intro after this much time without input.
+    pub attract_timeout: Option<std::time::Duration>,
+    // Game speed relative to the original 50 Hz clock.
+    speed_multiplier: f32,
+    // Speed used while slow motion key is held.
+    pub slow_motion_speed: f32,
+    // Pace frames with `script::sleep_precise` instead of plain sleeping.
+    pub precise_timing: bool,
+    // Pace frames on a 50 Hz vertical blank grid, see `script::pace_frame`.
+    pub reference_timing: bool,
+}
+
+impl Game {
+    // Nothing runs until a part is started with `restart_at`.
+    pub fn new(host: Box<dyn HostBackend>, mem: Memory) -> Self {
+        Self {
+            host,
+            video: VideoContext::new(),
+            vm: Vm::new(script::DEFAULT_CALL_STACK_SIZE),
+            mem,
+            music: Default::default(),
+            music_buf: Vec::new(),
+            current_part: 0,
+            next_part: None,
+            screen_num: None,
+            next_pal: None,
+            looping_gun_quirk: false,
+            bypass_protection: true,
+            input: Default::default(),
+            selected_task: None,
+            photo: None,
+            captions: None,
+            frame_count: 0,
+            start_time: std::time::Instant::now(),
+            speed_multiplier: 1.0,
+            slow_motion_speed: 0.25,
+            precise_timing: false,
+            reference_timing: false,
+            attract_timeout: None,
+            debug: None,
+        }
+    }
+
+    pub fn current_part(&self) -> u16 {
+        self.current_part
+    }
+
+    // Part requested by the scripts, started by the next frame.
+    pub fn next_part(&self) -> Option<u16> {
+        self.next_part
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn debug_state(&self) -> Option<&debug::DebugState> {
+        self.debug.as_deref()
+    }
+
+    pub fn input_snapshot(&self) -> script::InputState {
+        self.input.snapshot()
+    }
+
+    // Replaces input seen by the scripts on the next frame, e.g. to replay
+    // an `input_log::InputLog`.
+    pub fn set_input(&mut self, input: script::InputState) {
+        self.input.set_state(input);
+    }
+
+    // Time passed in the game itself: 50 Hz ticks requested by the scripts,
+    // independent of how fast frames actually ran. Differs from real time
+    // when paused, slowed down or run with `--frames`.
+    pub fn elapsed_game_time(&self) -> std::time::Duration {
+        std::time::Duration::from_millis(self.vm.ticks() * 20)
+    }
+
+    // Wall-clock time since the game was created.
+    pub fn elapsed_real_time(&self) -> std::time::Duration {
+        self.start_time.elapsed()
+    }
+
+    pub fn is_part_ready(&self, part: u16) -> bool {
+        mem::is_part_ready(&self.mem, part)
+    }
+
+    // Runs exactly one logical frame with given input, without any pacing,
+    // and returns the frame hash. See `lockstep` module for what must match
+    // between peers.
+    pub fn advance(&mut self, input: script::InputState) -> u64 {
+        self.set_input(input);
+        run_frame_unpaced(self);
+        lockstep::frame_hash(self)
+    }
+}
+
+pub const INTRO_PART: u16 = 16001;
+
+// Restarts the intro when `attract_timeout` passed without input. Paused
+// games and the intro itself are left alone.
+pub fn check_attract_mode(g: &mut Game) {
+    let timeout = match g.attract_timeout {
+        Some(timeout) => timeout,
+        None => return,
+    };
+    if g.host.wants_pause() || g.current_part == INTRO_PART || photo::is_active(g) {
+        return;
+    }
+    if g.host.idle_time() >= timeout {
+        log::info!("no input for {:?}, restarting the intro", timeout);
+        script::restart_at(g, INTRO_PART, -1);
+        g.host.reset_idle_time();
+    }
+}
+
+// Runs scripts for one frame, without waiting for the time it should take.
+// Use `script::pace_frame` afterwards to run at the original speed.
+pub fn run_frame(g: &mut Game) {
+    script::stage_tasks(g);
+    script::update_input(g);
+    script::run_tasks(g);
+
+    g.frame_count += 1;
+    if g.debug.is_some() {
+        debug::update(g);
+    }
+}
+
+// Reloads resources of current part and restarts it, see `watch` module.
+pub fn reload_data(g: &mut Game, mod_dir: Option<&std::path::Path>) {
+    if let Some(dir) = mod_dir {
+        match mem::read_overrides(dir) {
+            Ok(overrides) => g.mem.set_overrides(overrides),
+            Err(e) => log::warn!("{}", e),
+        }
+    }
+    log::info!("reloading part {}", g.current_part);
+    let part = g.current_part;
+    match mem::reload_current_part(g) {
+        Ok(()) => script::restart_at(g, part, -1),
+        Err(e) => {
+            log::error!("unable to reload part {}: {}", part, e);
+            g.host.request_quit();
+        }
+    }
+}
+
+// Runs a frame as fast as possible. Music is advanced by a fixed amount of
+// ticks to keep it deterministic.
+pub fn run_frame_unpaced(g: &mut Game) {
+    run_frame(g);
+    let ticks = g.vm.take_pause_slices();
+    host::produce_music_ticks(g, ticks);
+}
+
+// Timing of frames run by `benchmark`.
+#[derive(Debug, Clone, Copy)]
+pub struct BenchmarkResult {
+    pub frames: u64,
+    pub total: std::time::Duration,
+    // Hash of the last frame.
+    pub hash: u64,
+}
+
+impl BenchmarkResult {
+    pub fn average_frame(&self) -> Option<std::time::Duration> {
+        if self.frames == 0 {
+            None
+        } else {
+            Some(self.total.div_f64(self.frames as f64))
+        }
+    }
+}
+
+// Runs frames as fast as possible and measures how long they took. The final
+// hash tells whether runs with the same seed did the same work.
+pub fn benchmark(g: &mut Game, frames: u64) -> BenchmarkResult {
+    let start = std::time::Instant::now();
+    for _ in 0..frames {
+        run_frame_unpaced(g);
+    }
+    BenchmarkResult {
+        frames,
+        total: start.elapsed(),
+        hash: lockstep::frame_hash(g),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn frame_runs_on_null_host() {
+        // movi @FF, 2; fb_fill 1, 5; swap 1; yield; b 0x0000
+        let code = [
+            0x00, 0xFF, 0x00, 0x02, 0x0E, 0x01, 0x05, 0x10, 0x01, 0x06, 0x07, 0x00, 0x00,
+        ];
+        let mut g = testing::game("frame_runs_on_null_host", &code);
+
+        run_frame_unpaced(&mut g);
+
+        assert_eq!(g.frame_count(), 1);
+        assert_eq!(g.vm.ticks(), 2);
+        assert_eq!(g.video.front_page(), 1);
+        let page = g.video.read_page_indexed(1).unwrap();
+        assert!(page.iter().all(|&c| c == 5));
+        assert!(!g.host.wants_quit());
+    }
+
+    #[test]
+    fn benchmark_reports_frames_and_hash() {
+        let code = [0x00, 0xFF, 0x00, 0x01, 0x10, 0xFF, 0x06, 0x07, 0x00, 0x00];
+        let mut g = testing::game("benchmark_reports_frames_and_hash", &code);
+
+        let result = benchmark(&mut g, 50);
+
+        assert_eq!(result.frames, 50);
+        assert_eq!(g.frame_count(), 50);
+        assert_eq!(g.vm.ticks(), 50);
+        assert_eq!(result.hash, lockstep::frame_hash(&g));
+        let average = result.average_frame().unwrap();
+        assert!(average <= result.total);
+        assert!(average * 50 <= result.total + std::time::Duration::from_micros(50));
+        assert_eq!(benchmark(&mut g, 0).average_frame(), None);
+    }
+
+    #[test]
+    fn attract_mode_restarts_idle_game_only() {
+        let host = testing::TestHost::default();
+        let idle = host.idle.clone();
+        let paused = host.paused.clone();
+        let code = [0x06, 0x07, 0x00, 0x00];
+        let mut g = testing::game_with_host("attract_mode_restarts_idle_game_only", &code, host);
+        g.attract_timeout = Some(Duration::from_secs(60));
+
+        // Already in the intro.
+        idle.set(Duration::from_secs(90));
+        check_attract_mode(&mut g);
+        assert_eq!(idle.get(), Duration::from_secs(90));
+
+        g.current_part = INTRO_PART + 1;
+        paused.set(true);
+        check_attract_mode(&mut g);
+        assert_eq!(g.current_part(), INTRO_PART + 1);
+
+        paused.set(false);
+        idle.set(Duration::from_secs(59));
+        check_attract_mode(&mut g);
+        assert_eq!(g.current_part(), INTRO_PART + 1);
+
+        idle.set(Duration::from_secs(60));
+        check_attract_mode(&mut g);
+        assert_eq!(g.current_part(), INTRO_PART);
+        assert_eq!(idle.get(), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn reload_restores_resource_after_override_removed() {
+        let code = [0x06, 0x07, 0x00, 0x00];
+        let mut g = testing::game("reload_restores_resource_after_override_removed", &code);
+        let mod_dir = testing::temp_dir("reload_restores_resource_after_override_removed_mods");
+        let override_path = mod_dir.join(testing::INTRO_CODE.to_string());
+
+        let modded = [0x06, 0x06, 0x06, 0x07, 0x00, 0x00, 0x11];
+        std::fs::write(&override_path, modded).unwrap();
+        reload_data(&mut g, Some(&mod_dir));
+        assert_eq!(mem::code_segment(&g.mem), &modded[..]);
+
+        std::fs::remove_file(&override_path).unwrap();
+        reload_data(&mut g, Some(&mod_dir));
+        assert_eq!(mem::code_segment(&g.mem), &code[..]);
+        assert_eq!(
+            mem::entry_slice(&g.mem, testing::INTRO_CODE),
+            Some(&code[..])
+        );
+    }
+}
@@ -0,0 +1,144 @@
+// Support for running several games in lockstep (netplay, replays).
+//
+// Two games stay in sync when they start the same part with the same random
+// seed and receive the same `InputState` on every `Game::advance` call.
+// Everything the scripts can observe derives from those: VM registers and
+// tasks, loaded resources, framebuffers and the music position (which feeds
+// `MUSIC_SYNC` register). Wall-clock time only affects pacing, which is done
+// outside of `run_frame`, and `advance` mixes music by a fixed amount of
+// ticks instead of draining the audio device.
+//
+// The frame hash covers the current part, VM state and the front page. Any
+// mismatch between peers means they desynchronized.
+
+use crate::input_log::InputLog;
+use crate::Game;
+use std::path::Path;
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01B3;
+
+// FNV-1a is used instead of `DefaultHasher` as it must be stable between
+// builds and platforms.
+pub fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+    for b in bytes {
+        hash ^= u64::from(*b);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// Reads hashes written with `--hash-log`, one hexadecimal value per line
+// for every frame.
+pub fn read_hash_log(path: &Path) -> Result<Vec<u64>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| {
+            u64::from_str_radix(line.trim(), 16)
+                .map_err(|e| format!("{}:{}: {}", path.display(), i + 1, e))
+        })
+        .collect()
+}
+
+pub fn frame_hash(g: &Game) -> u64 {
+    let hash = fnv1a(FNV_OFFSET, &g.current_part.to_le_bytes());
+    let hash = g.vm.hash_state(hash);
+    let fb = g.video.front_page();
+    fnv1a(hash, &g.video.rndr.page(fb)[..])
+}
+
+// First frame of a replay whose hash differs from the recorded run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    pub frame: u64,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+// Replays recorded input with `Game::advance`, comparing the hash of every
+// frame with the one logged by the recorded run. Returns the number of
+// frames compared, fewer than logged when the input ends first.
+//
+// Music sync events only match when the recorded run mixed music by ticks
+// as well, which runs without audio output do.
+pub fn verify_replay(
+    g: &mut Game,
+    input: &InputLog,
+    expected: &[u64],
+) -> Result<usize, Divergence> {
+    for (&state, &want) in input.frames().iter().zip(expected) {
+        let frame = g.frame_count();
+        let hash = g.advance(state);
+        if hash != want {
+            return Err(Divergence {
+                frame,
+                expected: want,
+                actual: hash,
+            });
+        }
+    }
+    Ok(std::cmp::min(input.frames().len(), expected.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::script::InputState;
+    use crate::testing;
+
+    // yield; b 0x0000
+    const CODE: [u8; 4] = [0x06, 0x07, 0x00, 0x00];
+
+    fn recorded_run(name: &str, input: &InputLog) -> Vec<u64> {
+        let mut g = testing::game(name, &CODE);
+        g.vm.set_random_seed(1);
+        input
+            .frames()
+            .iter()
+            .map(|&state| g.advance(state))
+            .collect()
+    }
+
+    fn input_log() -> InputLog {
+        let mut input = InputLog::new();
+        for i in 0..20 {
+            input.push(InputState {
+                left: i % 3 == 0,
+                button: i >= 10,
+                ..Default::default()
+            });
+        }
+        input
+    }
+
+    #[test]
+    fn replay_matches_recorded_run() {
+        let input = input_log();
+        let expected = recorded_run("replay_matches_recorded_run_1", &input);
+
+        let mut g = testing::game("replay_matches_recorded_run_2", &CODE);
+        g.vm.set_random_seed(1);
+        assert_eq!(verify_replay(&mut g, &input, &expected), Ok(20));
+    }
+
+    #[test]
+    fn replay_reports_first_divergent_frame() {
+        let input = input_log();
+        let expected = recorded_run("replay_reports_first_divergent_frame_1", &input);
+
+        let mut changed = InputLog::new();
+        for (i, &state) in input.frames().iter().enumerate() {
+            changed.push(InputState {
+                up: i == 12,
+                ..state
+            });
+        }
+        let mut g = testing::game("replay_reports_first_divergent_frame_2", &CODE);
+        g.vm.set_random_seed(1);
+        let divergence = verify_replay(&mut g, &changed, &expected).unwrap_err();
+        assert_eq!(divergence.frame, 12);
+        assert_eq!(divergence.expected, expected[12]);
+    }
+}
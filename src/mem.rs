@@ -1,19 +1,36 @@
 use super::{video, Game};
+use crate::source::Source;
 use byteorder::{ByteOrder, BE};
-use std::io::{Read, Seek};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 
 const STATUS_EMPTY: u8 = 0;
 const STATUS_READY: u8 = 1;
 const STATUS_PENDING: u8 = 2;
 
 pub struct Memory {
+    source: Source,
+    // Directory with already unpacked entries, see `read_bank`.
+    cache_dir: Option<PathBuf>,
+    // Unpacked contents used instead of banks for some entries, see
+    // `read_overrides`.
+    overrides: HashMap<u16, Vec<u8>>,
     list: Vec<Entry>,
     pub data: Vec<u8>,
 
     data_bak: usize,
     data_cur: usize,
+    // Start of the area bitmaps are loaded to, at the end of `data`.
+    data_bmp: usize,
+    // Highest `data_cur` seen so far.
+    peak_usage: usize,
+    // Largest bitmap loaded so far.
+    bitmap_peak: usize,
 
     seg_code: usize,
+    seg_code_len: usize,
     seg_video_pal: usize,
     seg_video1: usize,
     seg_video2: usize,
@@ -29,6 +46,9 @@ struct Entry {
     bank_pos: u32,
     packed_size: usize,
     unpacked_size: usize,
+    // Size of data at `address`, which differs from `unpacked_size` when
+    // loaded from an override.
+    loaded_size: usize,
 }
 
 pub mod entry_kind {
@@ -42,23 +62,59 @@ pub mod entry_kind {
     pub const BANK: u8 = 6;
 }
 
-const DATA_SIZE: usize = 1024 * 1024;
-const DATA_BMP_OFFSET: usize = DATA_SIZE - 0x800 * 16;
+pub const DATA_SIZE: usize = 1024 * 1024;
+const BMP_AREA_SIZE: usize = 0x800 * 16;
 
 impl Memory {
-    pub fn new() -> Self {
-        let list = read_entries();
-        Self {
+    // `data_size` includes the area for bitmaps, see `peak_usage` for picking
+    // a smaller size than the default `DATA_SIZE`.
+    pub fn new(source: Source, data_size: usize) -> Result<Self, String> {
+        if data_size <= BMP_AREA_SIZE {
+            return Err(format!(
+                "data buffer of {} bytes is too small, need more than {}",
+                data_size, BMP_AREA_SIZE
+            ));
+        }
+        let list = read_entries(&source)?;
+        check_banks(&source, &list);
+        Ok(Self {
+            source,
+            cache_dir: None,
+            overrides: HashMap::new(),
             list,
-            data: vec![0; DATA_SIZE],
+            data: vec![0; data_size],
             data_bak: 0,
             data_cur: 0,
+            data_bmp: data_size - BMP_AREA_SIZE,
+            peak_usage: 0,
+            bitmap_peak: 0,
 
             seg_code: 0,
+            seg_code_len: 0,
             seg_video_pal: 0,
             seg_video1: 0,
             seg_video2: 0,
-        }
+        })
+    }
+
+    pub fn set_cache_dir(&mut self, dir: Option<PathBuf>) {
+        self.cache_dir = dir;
+    }
+
+    pub fn set_overrides(&mut self, overrides: HashMap<u16, Vec<u8>>) {
+        self.overrides = overrides;
+    }
+
+    // Most of `data` used by resources so far, not counting the fixed area
+    // for bitmaps.
+    pub fn peak_usage(&self) -> usize {
+        self.peak_usage
+    }
+
+    // Bytes of the bitmap area not needed by any bitmap loaded so far.
+    // Bitmaps are drawn as soon as they're loaded, so the area is reused.
+    pub fn bitmap_region_free(&self) -> usize {
+        BMP_AREA_SIZE - self.bitmap_peak
     }
 
     pub fn seg_code(&self) -> usize {
@@ -78,12 +134,19 @@ impl Memory {
     }
 }
 
-fn read_entries() -> Vec<Entry> {
-    let mut f = std::fs::File::open("memlist.bin").expect("`memlist.bin` file not found");
+// A list truncated before its terminator ends at the last complete entry.
+fn read_entries(source: &Source) -> Result<Vec<Entry>, String> {
+    let data = source
+        .read_all("memlist.bin")
+        .map_err(|e| format!("unable to read `memlist.bin`: {}", e))?;
+    let mut f = &data[..];
     let mut entries = Vec::new();
     let mut buf = [0; 20];
     loop {
-        f.read_exact(&mut buf).unwrap();
+        if f.read_exact(&mut buf).is_err() {
+            log::warn!("`memlist.bin` is truncated after {} entries", entries.len());
+            break;
+        }
         let status = buf[0];
         let kind = buf[1];
         let address = BE::read_u32(&buf[2..]) as usize;
@@ -106,27 +169,130 @@ fn read_entries() -> Vec<Entry> {
             bank_pos,
             packed_size,
             unpacked_size,
+            loaded_size: unpacked_size,
         })
     }
-    entries
+    if entries.is_empty() {
+        return Err("no entries in `memlist.bin`".to_owned());
+    }
+    Ok(entries)
 }
 
-fn read_bank(entry: &Entry, dst: &mut [u8]) {
-    let path = format!("bank{:02x}", entry.bank_num);
-    log::debug!("reading entry {:?} from {}", entry, path);
-    let mut f = std::fs::File::open(&path).unwrap();
-    f.seek(std::io::SeekFrom::Start(entry.bank_pos.into()))
-        .unwrap();
-    f.read_exact(&mut dst[0..entry.packed_size]).unwrap();
+// Incomplete data sets are allowed, resources from missing banks only fail
+// to load when needed.
+fn check_banks(source: &Source, entries: &[Entry]) {
+    let mut banks: Vec<u8> = entries
+        .iter()
+        .map(|e| e.bank_num)
+        .filter(|&b| b != 0)
+        .collect();
+    banks.sort_unstable();
+    banks.dedup();
+
+    for bank in banks {
+        if let Some(name) = bank_file_name(source, bank) {
+            if name != canonical_bank_name(bank) {
+                log::info!("using {} for bank {:02x}", name, bank);
+            }
+        } else {
+            let name = canonical_bank_name(bank);
+            let missing: Vec<_> = entries
+                .iter()
+                .enumerate()
+                .filter(|(_, e)| e.bank_num == bank)
+                .map(|(i, _)| i)
+                .collect();
+            log::warn!("missing {}, resources {:?} are unavailable", name, missing);
+        }
+    }
+}
+
+fn canonical_bank_name(bank: u8) -> String {
+    format!("bank{:02x}", bank)
+}
+
+// Data sets from other platforms may use different case or no zero padding.
+// The canonical lowercase name is tried first.
+fn bank_file_name(source: &Source, bank: u8) -> Option<String> {
+    let candidates = [
+        canonical_bank_name(bank),
+        format!("BANK{:02X}", bank),
+        format!("Bank{:02x}", bank),
+        format!("bank{:02X}", bank),
+        format!("BANK{:02x}", bank),
+        format!("bank{:x}", bank),
+        format!("BANK{:X}", bank),
+    ];
+    candidates
+        .iter()
+        .find(|name| source.contains(name))
+        .cloned()
+}
+
+// Packed entries are looked up in the cache directory first, if any, and
+// stored there after unpacking. Cached data of unexpected size is ignored.
+fn read_bank(
+    source: &Source,
+    cache_dir: Option<&Path>,
+    entry: &Entry,
+    dst: &mut [u8],
+) -> std::io::Result<()> {
+    let packed = entry.packed_size != entry.unpacked_size;
+    let cache_path = cache_dir.filter(|_| packed).map(|dir| {
+        dir.join(format!(
+            "bank{:02x}-{:08x}-{:x}",
+            entry.bank_num, entry.bank_pos, entry.packed_size
+        ))
+    });
+
+    if let Some(path) = &cache_path {
+        if let Ok(data) = std::fs::read(path) {
+            if data.len() == entry.unpacked_size {
+                log::debug!("reading entry {:?} from {}", entry, path.display());
+                dst[0..entry.unpacked_size].copy_from_slice(&data);
+                return Ok(());
+            }
+            log::warn!("ignoring {} with invalid size", path.display());
+        }
+    }
+
+    let name = bank_file_name(source, entry.bank_num)
+        .unwrap_or_else(|| canonical_bank_name(entry.bank_num));
+    log::debug!("reading entry {:?} from {}", entry, name);
+    source.read_at(&name, entry.bank_pos.into(), &mut dst[0..entry.packed_size])?;
+
+    if packed {
+        crate::bytekiller::unpack(&mut dst[0..entry.unpacked_size], entry.packed_size)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+    }
 
-    if entry.packed_size != entry.unpacked_size {
-        crate::bytekiller::unpack(&mut dst[0..entry.unpacked_size], entry.packed_size);
+    if let (Some(dir), Some(path)) = (cache_dir, &cache_path) {
+        let result = std::fs::create_dir_all(dir)
+            .and_then(|_| std::fs::write(path, &dst[0..entry.unpacked_size]));
+        if let Err(e) = result {
+            log::warn!("unable to write {}: {}", path.display(), e);
+        }
     }
+    Ok(())
+}
+
+// Fails when a resource of the part couldn't be loaded, leaving no part
+// loaded.
+pub fn setup_part(g: &mut Game, part_id: u16) -> Result<(), String> {
+    load_part(g, part_id, false)
 }
 
-pub fn setup_part(g: &mut Game, part_id: u16) {
+// Loads all segments of current part again, even though already loaded.
+pub fn reload_current_part(g: &mut Game) -> Result<(), String> {
+    if g.current_part != 0 {
+        load_part(g, g.current_part, true)?;
+    }
+    Ok(())
+}
+
+fn load_part(g: &mut Game, part_id: u16, force: bool) -> Result<(), String> {
     let m = &mut g.mem;
-    if g.current_part != part_id {
+    if force || g.current_part != part_id {
         assert!(
             (16000..=16009).contains(&part_id),
             "invalid part {}",
@@ -146,20 +312,145 @@ pub fn setup_part(g: &mut Game, part_id: u16) {
             m.list[usize::from(i)].status = STATUS_PENDING;
         }
 
+        g.current_part = 0;
         load_entries(g);
 
         let m = &mut g.mem;
-        m.seg_video_pal = address_of_entry(m, ipal).unwrap();
-        m.seg_code = address_of_entry(m, icod).unwrap();
-        m.seg_video1 = address_of_entry(m, ivd1).unwrap();
+        let segment = |m: &Memory, index: u8| {
+            address_of_entry(m, index)
+                .ok_or_else(|| format!("resource {} of part {} is unavailable", index, part_id))
+        };
+        m.seg_video_pal = segment(m, ipal)?;
+        m.seg_code = segment(m, icod)?;
+        m.seg_code_len = m.list[usize::from(icod)].loaded_size;
+        m.seg_video1 = segment(m, ivd1)?;
         if ivd2 != 0 {
-            m.seg_video2 = address_of_entry(m, ivd2).unwrap();
+            m.seg_video2 = segment(m, ivd2)?;
         }
 
         g.current_part = part_id;
     }
 
     g.mem.data_bak = g.mem.data_cur;
+    Ok(())
+}
+
+// Script bytecode of current part, empty before any part is loaded.
+pub fn code_segment(m: &Memory) -> &[u8] {
+    &m.data[m.seg_code..m.seg_code + m.seg_code_len]
+}
+
+// Whether all segments of given part are loaded. Loading is synchronous, so
+// this holds right after `setup_part` for the same part.
+pub fn is_part_ready(m: &Memory, part_id: u16) -> bool {
+    if !(16000..=16009).contains(&part_id) {
+        return false;
+    }
+    let (ipal, icod, ivd1, ivd2) = MEM_LIST_PARTS[usize::from(part_id - 16000)];
+    [ipal, icod, ivd1, ivd2]
+        .iter()
+        .copied()
+        .filter(|x| *x != 0)
+        .all(|i| m.list[usize::from(i)].status == STATUS_READY)
+}
+
+// Issue with resources referenced by a part, found by `validate_parts`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Problem {
+    // Index past the end of the resource list.
+    OutOfRange { part: u16, index: u8 },
+    // Entry not stored in any bank.
+    BankZero { part: u16, index: u8 },
+    // Entry stored in a bank which wasn't found.
+    MissingBank { part: u16, index: u8, bank: u8 },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match *self {
+            Problem::OutOfRange { part, index } => {
+                write!(f, "part {}: resource 0x{:02X} is out of range", part, index)
+            }
+            Problem::BankZero { part, index } => {
+                write!(f, "part {}: resource 0x{:02X} is in bank 0", part, index)
+            }
+            Problem::MissingBank { part, index, bank } => write!(
+                f,
+                "part {}: resource 0x{:02X} is in missing {}",
+                part,
+                index,
+                canonical_bank_name(bank)
+            ),
+        }
+    }
+}
+
+// Checks that every part can load all of its resources, without loading
+// anything. Overridden resources are always available.
+pub fn validate_parts(m: &Memory) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    for (part, &(ipal, icod, ivd1, ivd2)) in (16000..).zip(MEM_LIST_PARTS.iter()) {
+        for index in [ipal, icod, ivd1, ivd2].iter().copied().filter(|x| *x != 0) {
+            if m.overrides.contains_key(&u16::from(index)) {
+                continue;
+            }
+            let problem = match m.list.get(usize::from(index)) {
+                None => Problem::OutOfRange { part, index },
+                Some(e) if e.bank_num == 0 => Problem::BankZero { part, index },
+                Some(e) if bank_file_name(&m.source, e.bank_num).is_none() => {
+                    Problem::MissingBank {
+                        part,
+                        index,
+                        bank: e.bank_num,
+                    }
+                }
+                Some(_) => continue,
+            };
+            problems.push(problem);
+        }
+    }
+    problems
+}
+
+// Part following given one in the story, in the order of `MEM_LIST_PARTS`:
+// protection screens, introduction, water, jail, 'cite', 'arene', 'luxe'
+// and 'final'. There is nothing after the final part, and password screens
+// lead wherever the entered code does.
+pub fn next_story_part(part_id: u16) -> Option<u16> {
+    match part_id {
+        16000..=16006 => Some(part_id + 1),
+        _ => None,
+    }
+}
+
+// Appends a loaded entry with given contents, returning its index. Meant
+// for synthetic resources like `sfx::synth_sample`.
+#[cfg(test)]
+pub fn add_entry(m: &mut Memory, kind: u8, contents: &[u8]) -> Option<u16> {
+    let index = u16::try_from(m.list.len()).ok()?;
+    if contents.len() > m.data_bmp - m.data_cur {
+        return None;
+    }
+    let address = m.data_cur;
+    m.data[address..address + contents.len()].copy_from_slice(contents);
+    m.data_cur += contents.len();
+    m.peak_usage = std::cmp::max(m.peak_usage, m.data_cur);
+    m.list.push(Entry {
+        status: STATUS_READY,
+        kind,
+        address,
+        rank_num: 0,
+        bank_num: 0,
+        bank_pos: 0,
+        packed_size: contents.len(),
+        unpacked_size: contents.len(),
+        loaded_size: contents.len(),
+    });
+    Some(index)
+}
+
+pub fn kind_of_entry(m: &Memory, index: impl Into<usize>) -> Option<u8> {
+    m.list.get(index.into()).map(|e| e.kind)
 }
 
 pub fn address_of_entry(m: &Memory, index: impl Into<usize>) -> Option<usize> {
@@ -171,12 +462,23 @@ pub fn address_of_entry(m: &Memory, index: impl Into<usize>) -> Option<usize> {
     }
 }
 
+// Data of loaded entry bounded by its unpacked size, so readers can't run
+// past the end of the resource.
+pub fn entry_slice(m: &Memory, index: impl Into<usize>) -> Option<&[u8]> {
+    let entry = m.list.get(index.into())?;
+    if entry.status == STATUS_READY {
+        m.data.get(entry.address..entry.address + entry.loaded_size)
+    } else {
+        None
+    }
+}
+
 pub fn address_of_entry_with_kind(
     m: &Memory,
     index: impl Into<usize> + Copy,
     kind: u8,
 ) -> Option<usize> {
-    let entry = &m.list[index.into()];
+    let entry = m.list.get(index.into())?;
     if entry.kind == kind {
         address_of_entry(m, index)
     } else {
@@ -200,34 +502,114 @@ pub fn load_entry(g: &mut Game, num: u16) {
     }
 }
 
+// Reads replacements for resources from given directory. Every file holds
+// unpacked contents of a single resource and is named by its number, in
+// decimal or hexadecimal with "0x" prefix. Other files are ignored.
+pub fn read_overrides(dir: &Path) -> Result<HashMap<u16, Vec<u8>>, String> {
+    let entries =
+        std::fs::read_dir(dir).map_err(|e| format!("unable to read {}: {}", dir.display(), e))?;
+    let mut overrides = HashMap::new();
+    for entry in entries {
+        let path = entry
+            .map_err(|e| format!("unable to read {}: {}", dir.display(), e))?
+            .path();
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let num = match name.strip_prefix("0x") {
+            Some(hex) => u16::from_str_radix(hex, 16),
+            None => name.parse(),
+        };
+        if let Ok(num) = num {
+            let contents = std::fs::read(&path)
+                .map_err(|e| format!("unable to read {}: {}", path.display(), e))?;
+            log::info!("resource {} overridden by {}", num, path.display());
+            overrides.insert(num, contents);
+        }
+    }
+    Ok(overrides)
+}
+
+// Copies override of given entry to `dst`, returning its size. Bitmaps must
+// keep their size, as they're converted right away. The entry itself is left
+// as listed, so the original is loaded once the override is removed.
+fn load_override(entry: &Entry, contents: &[u8], dst: &mut [u8]) -> Result<usize, String> {
+    if entry.kind == entry_kind::BITMAP && contents.len() != entry.unpacked_size {
+        return Err(format!(
+            "bitmap must have {} bytes, got {}",
+            entry.unpacked_size,
+            contents.len()
+        ));
+    }
+    if contents.len() > dst.len() {
+        return Err(format!("{} bytes don't fit in data buffer", contents.len()));
+    }
+    dst[..contents.len()].copy_from_slice(contents);
+    Ok(contents.len())
+}
+
 fn load_entries(g: &mut Game) {
     let m = &mut g.mem;
-    while let Some(entry) = m
+    // Highest rank first, ties go to the highest index. Load order decides
+    // where entries end up in `data`, so it must stay deterministic.
+    while let Some((index, entry)) = m
         .list
         .iter_mut()
-        .filter(|e| e.status == STATUS_PENDING)
-        .max_by_key(|e| e.rank_num)
+        .enumerate()
+        .filter(|(_, e)| e.status == STATUS_PENDING)
+        .max_by_key(|(i, e)| (e.rank_num, *i))
     {
-        let address = if entry.kind == entry_kind::BITMAP {
-            DATA_BMP_OFFSET
+        let (address, end) = if entry.kind == entry_kind::BITMAP {
+            (m.data_bmp, m.data.len())
         } else {
-            assert!(entry.unpacked_size <= DATA_BMP_OFFSET - m.data_cur);
-            m.data_cur
+            (m.data_cur, m.data_bmp)
         };
 
-        if entry.bank_num == 0 {
-            log::warn!("invalid load from bank 0");
-            entry.status = STATUS_EMPTY;
-        } else {
-            read_bank(entry, &mut m.data[address..]);
-            if entry.kind == entry_kind::BITMAP {
-                video::copy_bitmap(&mut g.video, &m.data[address..]);
+        let overrides = &m.overrides;
+        let over = u16::try_from(index).ok().and_then(|i| overrides.get(&i));
+        let result = match over {
+            Some(contents) => load_override(entry, contents, &mut m.data[address..end])
+                .map_err(|e| format!("invalid override: {}", e)),
+            None if entry.bank_num == 0 => Err("invalid load from bank 0".to_owned()),
+            None if entry.kind == entry_kind::BITMAP && entry.unpacked_size > end - address => {
+                Err(format!(
+                    "bitmap of {} bytes doesn't fit in bitmap area",
+                    entry.unpacked_size
+                ))
+            }
+            None if entry.unpacked_size > end - address => Err(format!(
+                "{} bytes don't fit in data buffer with {} of {} bytes used, peak usage so far is {}",
+                entry.unpacked_size, address, m.data_bmp, m.peak_usage
+            )),
+            None => {
+                read_bank(
+                    &m.source,
+                    m.cache_dir.as_deref(),
+                    entry,
+                    &mut m.data[address..end],
+                )
+                .map(|()| entry.unpacked_size)
+                .map_err(|e| e.to_string())
+            }
+        };
+
+        let size = match result {
+            Ok(size) => size,
+            Err(e) => {
+                log::error!("unable to load resource {}: {}", index, e);
                 entry.status = STATUS_EMPTY;
-            } else {
-                entry.address = address;
-                entry.status = STATUS_READY;
-                m.data_cur += entry.unpacked_size;
+                continue;
             }
+        };
+        entry.loaded_size = size;
+        if entry.kind == entry_kind::BITMAP {
+            m.bitmap_peak = std::cmp::max(m.bitmap_peak, size);
+            let bitmap = &m.data[address..address + size];
+            video::copy_bitmap(&mut g.video, bitmap, index);
+            entry.status = STATUS_EMPTY;
+        } else {
+            entry.address = address;
+            entry.status = STATUS_READY;
+            m.data_cur += size;
+            m.peak_usage = std::cmp::max(m.peak_usage, m.data_cur);
         }
     }
 }
@@ -244,3 +626,263 @@ const MEM_LIST_PARTS: [(u8, u8, u8, u8); 10] = [
     (0x7D, 0x7E, 0x7F, 0x00), // 16008 - password screen
     (0x7D, 0x7E, 0x7F, 0x00), // 16009 - password screen
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn peak_usage_covers_loaded_part() {
+        let code = [0x06, 0x07, 0x00, 0x00];
+        let g = testing::game("peak_usage_covers_loaded_part", &code);
+        assert!(is_part_ready(&g.mem, crate::INTRO_PART));
+        assert_eq!(g.mem.peak_usage(), 2048 + code.len() + 16);
+    }
+
+    #[test]
+    fn code_segment_spans_code_resource() {
+        let dir = testing::temp_dir("code_segment_spans_code_resource");
+        let code = [0x06, 0x07, 0x00, 0x00, 0x11];
+        testing::write_data_set(
+            &dir,
+            &[
+                (testing::INTRO_PALETTE, entry_kind::PALETTE, &[0xAA; 2048]),
+                (testing::INTRO_CODE, entry_kind::BYTECODE, &code),
+                (testing::INTRO_SHAPES, entry_kind::SHAPE, &[0xAA; 16]),
+            ],
+        );
+        let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        assert!(code_segment(&m).is_empty());
+        let g = testing::game_with_memory(m);
+
+        assert_eq!(code_segment(&g.mem), &code[..]);
+    }
+
+    #[test]
+    fn part_larger_than_data_buffer_fails_without_panicking() {
+        let dir = testing::temp_dir("part_larger_than_data_buffer_fails_without_panicking");
+        let code = [0x06; 200];
+        testing::write_data_set(&dir, &[(testing::INTRO_CODE, entry_kind::BYTECODE, &code)]);
+        let m = Memory::new(Source::detect(&dir).unwrap(), BMP_AREA_SIZE + 100).unwrap();
+        let g = testing::game_with_memory(m);
+
+        assert!(!is_part_ready(&g.mem, crate::INTRO_PART));
+        assert_eq!(g.current_part(), 0);
+        assert!(g.host.wants_quit());
+        assert!(g.vm.all_tasks_halted());
+    }
+
+    #[test]
+    fn entry_slice_is_bounded_by_unpacked_size() {
+        let code = [0x06, 0x07, 0x00, 0x00, 0x11];
+        let g = testing::game("entry_slice_is_bounded_by_unpacked_size", &code);
+
+        for &index in &[
+            testing::INTRO_PALETTE,
+            testing::INTRO_CODE,
+            testing::INTRO_SHAPES,
+        ] {
+            let entry = &g.mem.list[usize::from(index)];
+            let slice = entry_slice(&g.mem, index).unwrap();
+            assert_eq!(slice.len(), entry.unpacked_size);
+            assert_eq!(slice.as_ptr(), g.mem.data[entry.address..].as_ptr());
+        }
+        assert_eq!(entry_slice(&g.mem, testing::INTRO_CODE), Some(&code[..]));
+        // Listed, but not loaded.
+        assert_eq!(entry_slice(&g.mem, 0x7Fu16), None);
+        assert_eq!(entry_slice(&g.mem, g.mem.list.len()), None);
+    }
+
+    #[test]
+    fn cache_is_used_after_miss() {
+        let dir = testing::temp_dir("cache_is_used_after_miss");
+        let cache = dir.join("cache");
+        let code = [0x06; 256];
+        testing::write_packed_data_set(&dir, &[(testing::INTRO_CODE, entry_kind::BYTECODE, &code)]);
+        let mut m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        let entry = &m.list[usize::from(testing::INTRO_CODE)];
+        assert!(entry.packed_size < entry.unpacked_size);
+        m.set_cache_dir(Some(cache.clone()));
+        let mut g = testing::game_with_memory(m);
+
+        // Miss unpacks and stores the data.
+        assert_eq!(code_segment(&g.mem), &code[..]);
+        let files: Vec<_> = std::fs::read_dir(&cache)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(std::fs::read(&files[0]).unwrap(), &code[..]);
+
+        // Hit reads what's in the cache.
+        let mut cached = code;
+        cached[255] = 0x11;
+        std::fs::write(&files[0], &cached[..]).unwrap();
+        reload_current_part(&mut g).unwrap();
+        assert_eq!(code_segment(&g.mem), &cached[..]);
+    }
+
+    #[test]
+    fn missing_bank_fails_only_when_needed() {
+        let dir = testing::temp_dir("missing_bank_fails_only_when_needed");
+        let code = [0x06, 0x07, 0x00, 0x00];
+        testing::write_data_set(
+            &dir,
+            &[
+                (testing::INTRO_CODE, entry_kind::BYTECODE, &code),
+                (0x30, entry_kind::SOUND, &[1; 16]),
+                (0x31, entry_kind::SOUND, &[2; 16]),
+            ],
+        );
+        // Move resource 0x30 to bank 2, which doesn't exist.
+        let memlist = dir.join("memlist.bin");
+        let mut list = std::fs::read(&memlist).unwrap();
+        list[0x30 * 20 + 7] = 2;
+        std::fs::write(&memlist, list).unwrap();
+
+        let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        let mut g = testing::game_with_memory(m);
+        assert!(is_part_ready(&g.mem, crate::INTRO_PART));
+
+        load_entry(&mut g, 0x31);
+        assert_eq!(entry_slice(&g.mem, 0x31u16), Some(&[2; 16][..]));
+        load_entry(&mut g, 0x30);
+        assert_eq!(entry_slice(&g.mem, 0x30u16), None);
+        assert!(!g.host.wants_quit());
+    }
+
+    #[test]
+    fn tied_ranks_load_highest_index_first() {
+        let dir = testing::temp_dir("tied_ranks_load_highest_index_first");
+        let code = [0x06, 0x07, 0x00, 0x00];
+        testing::write_data_set(
+            &dir,
+            &[
+                (testing::INTRO_CODE, entry_kind::BYTECODE, &code),
+                (0x30, entry_kind::SOUND, &[1; 16]),
+                (0x31, entry_kind::SOUND, &[2; 16]),
+                (0x32, entry_kind::SOUND, &[3; 16]),
+            ],
+        );
+        let memlist = dir.join("memlist.bin");
+        let mut list = std::fs::read(&memlist).unwrap();
+        for &(index, rank) in &[(0x30, 1), (0x31, 1), (0x32, 2)] {
+            list[index * 20 + 6] = rank;
+        }
+        std::fs::write(&memlist, list).unwrap();
+
+        let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        let mut g = testing::game_with_memory(m);
+        for index in 0x30..=0x32 {
+            g.mem.list[index].status = STATUS_PENDING;
+        }
+        load_entries(&mut g);
+
+        let start = g.mem.list[0x32].address;
+        assert_eq!(g.mem.list[0x31].address, start + 16);
+        assert_eq!(g.mem.list[0x30].address, start + 32);
+    }
+
+    #[test]
+    fn memlist_without_terminator_ends_at_eof() {
+        let dir = testing::temp_dir("memlist_without_terminator_ends_at_eof");
+        testing::write_data_set(&dir, &[(0x30, entry_kind::SOUND, &[1; 16])]);
+        let memlist = dir.join("memlist.bin");
+        let mut list = std::fs::read(&memlist).unwrap();
+        // Drop the terminator and half of the last entry.
+        list.truncate(0x31 * 20 - 10);
+        std::fs::write(&memlist, &list).unwrap();
+
+        let entries = read_entries(&Source::detect(&dir).unwrap()).unwrap();
+        assert_eq!(entries.len(), 0x30);
+
+        std::fs::write(&memlist, &list[..10]).unwrap();
+        assert!(read_entries(&Source::detect(&dir).unwrap()).is_err());
+    }
+
+    #[test]
+    fn uppercase_and_unpadded_bank_names_load() {
+        for name in &["BANK01", "bank1"] {
+            let dir =
+                testing::temp_dir(&format!("uppercase_and_unpadded_bank_names_load_{}", name));
+            let code = [0x06, 0x07, 0x00, 0x00];
+            testing::write_data_set(&dir, &[(testing::INTRO_CODE, entry_kind::BYTECODE, &code)]);
+            std::fs::rename(dir.join("bank01"), dir.join(name)).unwrap();
+
+            let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+            let g = testing::game_with_memory(m);
+
+            assert!(is_part_ready(&g.mem, crate::INTRO_PART), "{}", name);
+            assert_eq!(code_segment(&g.mem), &code[..]);
+        }
+    }
+
+    #[test]
+    fn validate_parts_reports_broken_references() {
+        let dir = testing::temp_dir("validate_parts_reports_broken_references");
+        testing::write_data_set(&dir, &[]);
+        let memlist = dir.join("memlist.bin");
+        let mut list = std::fs::read(&memlist).unwrap();
+        list[0x18 * 20 + 7] = 0;
+        list[0x1A * 20 + 7] = 3;
+        // End the list before the password screen resources.
+        list.truncate(0x7D * 20);
+        list.push(0xFF);
+        list.resize(list.len() + 19, 0);
+        std::fs::write(&memlist, list).unwrap();
+
+        let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        let mut expected = vec![
+            Problem::BankZero {
+                part: 16001,
+                index: 0x18,
+            },
+            Problem::MissingBank {
+                part: 16002,
+                index: 0x1A,
+                bank: 3,
+            },
+        ];
+        for &part in &[16008, 16009] {
+            for &index in &[0x7D, 0x7E, 0x7F] {
+                expected.push(Problem::OutOfRange { part, index });
+            }
+        }
+        assert_eq!(validate_parts(&m), expected);
+    }
+
+    #[test]
+    fn bitmaps_stay_within_bitmap_area() {
+        let dir = testing::temp_dir("bitmaps_stay_within_bitmap_area");
+        let code = [0x06, 0x07, 0x00, 0x00];
+        let bitmap = vec![0xFF; video::BITMAP_SIZE];
+        let oversized = vec![0xFF; BMP_AREA_SIZE + 1];
+        testing::write_data_set(
+            &dir,
+            &[
+                (testing::INTRO_CODE, entry_kind::BYTECODE, &code),
+                (0x40, entry_kind::BITMAP, &bitmap),
+                (0x41, entry_kind::BITMAP, &oversized),
+            ],
+        );
+        let m = Memory::new(Source::detect(&dir).unwrap(), DATA_SIZE).unwrap();
+        let mut g = testing::game_with_memory(m);
+        assert_eq!(g.mem.bitmap_region_free(), BMP_AREA_SIZE);
+
+        load_entry(&mut g, 0x40);
+        assert_eq!(
+            g.mem.bitmap_region_free(),
+            BMP_AREA_SIZE - video::BITMAP_SIZE
+        );
+        assert_eq!(entry_slice(&g.mem, 0x40u16), None);
+        assert!(g.video.rndr.page(0).iter().all(|&b| b == 0xF));
+
+        load_entry(&mut g, 0x41);
+        assert_eq!(
+            g.mem.bitmap_region_free(),
+            BMP_AREA_SIZE - video::BITMAP_SIZE
+        );
+        assert!(!g.host.wants_quit());
+    }
+}
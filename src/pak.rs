@@ -3,12 +3,55 @@ use std::cell::RefCell;
 use std::io::{self, Read, Seek};
 
 const MAX_NAME_LEN: usize = 32;
+const HEADER_SIZE: usize = 12;
+const DIR_ENTRY_SIZE: usize = 0x40;
 
 pub struct Package {
     file: RefCell<std::fs::File>,
     entries: Vec<Entry>,
 }
 
+#[derive(Debug)]
+pub enum PakError {
+    Io(io::Error),
+    // Encoded entry of given size is too short or not a multiple of 4 bytes.
+    BadLength(usize),
+    // For checksum validation of decoded entries.
+    ChecksumMismatch { expected: u32, actual: u32 },
+}
+
+impl std::fmt::Display for PakError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PakError::Io(e) => e.fmt(f),
+            PakError::BadLength(len) => write!(f, "invalid length {} of encoded entry", len),
+            PakError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch, expected {:08x}, got {:08x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PakError {}
+
+impl From<io::Error> for PakError {
+    fn from(e: io::Error) -> Self {
+        PakError::Io(e)
+    }
+}
+
+// Corrupt entries are reported as invalid data.
+impl From<PakError> for io::Error {
+    fn from(e: PakError) -> Self {
+        match e {
+            PakError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Entry {
     name: [u8; MAX_NAME_LEN],
@@ -17,13 +60,48 @@ pub struct Entry {
 }
 
 impl Package {
-    // TODO: open
+    pub fn open(path: &std::path::Path) -> io::Result<Self> {
+        let mut f = std::fs::File::open(path)?;
+
+        let mut header = [0; HEADER_SIZE];
+        f.read_exact(&mut header)?;
+        if &header[0..4] != b"PACK" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a package file",
+            ));
+        }
+        let dir_offset = LittleEndian::read_u32(&header[4..]);
+        let dir_size = LittleEndian::read_u32(&header[8..]) as usize;
+
+        let mut dir = vec![0; dir_size - dir_size % DIR_ENTRY_SIZE];
+        f.seek(io::SeekFrom::Start(dir_offset.into()))?;
+        f.read_exact(&mut dir)?;
+
+        let entries = dir
+            .chunks_exact(DIR_ENTRY_SIZE)
+            .map(|e| {
+                let mut name = [0; MAX_NAME_LEN];
+                name.copy_from_slice(&e[0..MAX_NAME_LEN]);
+                Entry {
+                    name,
+                    offset: LittleEndian::read_u32(&e[0x38..]),
+                    size: LittleEndian::read_u32(&e[0x3C..]),
+                }
+            })
+            .collect();
+
+        Ok(Self {
+            file: RefCell::new(f),
+            entries,
+        })
+    }
 
     pub fn find(&self, name: &str) -> Option<&Entry> {
         self.entries.iter().find(|e| e.name_equals(name))
     }
 
-    pub fn load(&self, entry: &Entry) -> io::Result<Vec<u8>> {
+    pub fn load(&self, entry: &Entry) -> Result<Vec<u8>, PakError> {
         let mut f = self.file.borrow_mut();
         f.seek(io::SeekFrom::Start(entry.offset.into()))?;
         let length = entry.size as usize;
@@ -31,7 +109,10 @@ impl Package {
         f.read_exact(&mut data)?;
 
         if data.starts_with(b"TooDC") {
-            decode_toodc(&mut data[6..]);
+            if data.len() < 10 {
+                return Err(PakError::BadLength(data.len()));
+            }
+            decode_toodc(&mut data[6..])?;
             data.drain(0..10);
         }
 
@@ -62,13 +143,10 @@ impl Entry {
     }
 }
 
-const CHECKSUM: u32 = 0x2020_2020;
-
-fn decode_toodc(data: &mut [u8]) {
-    assert!(
-        data.len().trailing_zeros() >= 2,
-        "invalid length for encoded TooDC data"
-    );
+fn decode_toodc(data: &mut [u8]) -> Result<(), PakError> {
+    if data.len().trailing_zeros() < 2 {
+        return Err(PakError::BadLength(data.len()));
+    }
 
     const XOR_KEY2: u32 = 0x2268_3297;
 
@@ -81,4 +159,28 @@ fn decode_toodc(data: &mut [u8]) {
         acc += 0x4D;
         LittleEndian::write_u32(q, word);
     }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn truncated_toodc_entry_is_bad_length() {
+        let path = testing::temp_dir("truncated_toodc_entry_is_bad_length").join("test.paq");
+        testing::write_pak(
+            &path,
+            &[
+                ("short", b"TooDC\0"),
+                ("unaligned", b"TooDC\0\0\0\0\0\x01\x02\x03"),
+            ],
+        );
+        let pak = Package::open(&path).unwrap();
+
+        let load = |name| pak.load(pak.find(name).unwrap());
+        assert!(matches!(load("short"), Err(PakError::BadLength(6))));
+        assert!(matches!(load("unaligned"), Err(PakError::BadLength(7))));
+    }
 }
@@ -0,0 +1,81 @@
+// Photo mode freezes the game and lets the user look at any page with any
+// palette and save what is shown. Game state is left as it was, except the
+// palette which is restored on leave.
+
+use crate::{host, video, Game};
+
+pub struct PhotoMode {
+    page: u8,
+    pal: Option<u8>,
+    saved_pal: Option<u8>,
+    shots: u32,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Action {
+    Toggle,
+    PrevPage,
+    NextPage,
+    PrevPal,
+    NextPal,
+    Screenshot,
+}
+
+const PAGE_COUNT: u8 = 4;
+const PAL_COUNT: u8 = 32;
+
+pub fn is_active(g: &Game) -> bool {
+    g.photo.is_some()
+}
+
+pub fn apply(g: &mut Game, action: Action) {
+    let photo = match (&mut g.photo, action) {
+        (None, Action::Toggle) => {
+            let pal = g.video.current_pal_num();
+            g.photo = Some(PhotoMode {
+                page: g.video.front_page(),
+                pal,
+                saved_pal: pal,
+                shots: 0,
+            });
+            return;
+        }
+        (None, _) => return,
+        (Some(photo), _) => photo,
+    };
+
+    match action {
+        Action::Toggle => {
+            let saved_pal = photo.saved_pal;
+            g.photo = None;
+            if let Some(num) = saved_pal {
+                video::load_pal_unforced(g, num);
+            }
+            let fb = g.video.front_page();
+            host::display_surface(g, fb);
+            return;
+        }
+        Action::PrevPage => photo.page = (photo.page + PAGE_COUNT - 1) % PAGE_COUNT,
+        Action::NextPage => photo.page = (photo.page + 1) % PAGE_COUNT,
+        Action::PrevPal => {
+            photo.pal = Some(photo.pal.map_or(0, |n| (n + PAL_COUNT - 1) % PAL_COUNT))
+        }
+        Action::NextPal => photo.pal = Some(photo.pal.map_or(0, |n| (n + 1) % PAL_COUNT)),
+        Action::Screenshot => {
+            let path = format!("photo{:03}.png", photo.shots);
+            photo.shots += 1;
+            let image = g.video.rndr.page(photo.page);
+            match video::write_png(path.as_ref(), &image[..], g.video.rndr.palette()) {
+                Ok(()) => log::info!("saved {}", path),
+                Err(e) => log::warn!("unable to write {}: {}", path, e),
+            }
+            return;
+        }
+    }
+
+    let (page, pal) = (photo.page, photo.pal);
+    if let Some(num) = pal {
+        video::load_pal_unforced(g, num);
+    }
+    host::display_surface(g, page);
+}
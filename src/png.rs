@@ -0,0 +1,89 @@
+// Minimal PNG writer for indexed images. Data is stored uncompressed, which
+// is good enough for dumping assets.
+
+use crate::video::RgbColor;
+use std::io::{self, Write};
+
+const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+const COLOR_TYPE_INDEXED: u8 = 3;
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+pub fn write_indexed(
+    w: &mut impl Write,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+    pal: &[RgbColor],
+) -> io::Result<()> {
+    assert_eq!(pixels.len(), (width * height) as usize);
+    assert!(pal.len() <= 256);
+
+    w.write_all(SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // bit depth, color type, compression, filter, interlace
+    header.extend_from_slice(&[8, COLOR_TYPE_INDEXED, 0, 0, 0]);
+    write_chunk(w, b"IHDR", &header)?;
+
+    let plte: Vec<u8> = pal.iter().flat_map(|c| vec![c.r, c.g, c.b]).collect();
+    write_chunk(w, b"PLTE", &plte)?;
+
+    let mut raw = Vec::with_capacity(pixels.len() + height as usize);
+    for row in pixels.chunks_exact(width as usize) {
+        // no filtering
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+    write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+
+    write_chunk(w, b"IEND", &[])
+}
+
+fn write_chunk(w: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    let crc = crc32(crc32(!0, kind), data);
+    w.write_all(&(!crc).to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let block_count = std::cmp::max(1, data.len().div_ceil(MAX_STORED_BLOCK));
+    for i in 0..block_count {
+        let begin = i * MAX_STORED_BLOCK;
+        let end = std::cmp::min(begin + MAX_STORED_BLOCK, data.len());
+        let len = (end - begin) as u16;
+        out.push(u8::from(i + 1 == block_count));
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(&data[begin..end]);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= u32::from(*b);
+        for _ in 0..8 {
+            crc = if (crc & 1) != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for x in data {
+        a = (a + u32::from(*x)) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
@@ -1,8 +1,9 @@
-use super::{mem, sfx, video, Game};
+use super::{lockstep, mem, sfx, video, Game};
 use rand::Rng;
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
-const CALL_STACK_SIZE: u8 = 64;
+pub const DEFAULT_CALL_STACK_SIZE: usize = 64;
 const TASK_COUNT: usize = 64;
 
 // Special program counter values to halt tasks.
@@ -10,6 +11,13 @@ const HALT_PC: u16 = 0xFFFF;
 const PRE_HALT_PC: u16 = 0xFFFE;
 
 mod reg_id {
+    // Copy protection, see `ProtectionState`.
+    pub const PROTECTION_EXPECTED: usize = 0x1E;
+    pub const PROTECTION_ENTERED: usize = 0x29;
+    pub const PROTECTION_COUNTER_1: usize = 0x32;
+    pub const PROTECTION_FLAGS: usize = 0x54;
+    pub const PROTECTION_COUNTER_2: usize = 0x64;
+
     pub const RANDOM_SEED: usize = 0x3C;
     pub const SCREEN_NUM: usize = 0x67;
     pub const LAST_KEYCHAR: usize = 0xDA;
@@ -24,6 +32,16 @@ mod reg_id {
     pub const PAUSE_SLICES: usize = 0xFF;
 }
 
+// Palette fixups for the DOS data set, which is missing some palette changes
+// or requests wrong ones. The screen ones are applied for every data set.
+
+// (part, screen, palette) loaded on entering given screen.
+const SCREEN_PAL_FIXUPS: &[(u16, i16, u8)] = &[(16004, 0x47, 8), (16006, 0x4A, 1)];
+// (part, palette) changes requested by scripts which are ignored.
+const SKIPPED_PAL_CHANGES: &[(u16, u8)] = &[(16001, 10), (16001, 16)];
+// (part, palette) loaded on part start.
+const PART_START_PALS: &[(u16, u8)] = &[(16009, 5)];
+
 #[derive(Debug, Clone, Copy)]
 struct Task {
     pc: u16,
@@ -41,28 +59,43 @@ impl Default for Task {
 
 pub struct Vm {
     regs: [i16; 256],
-    call_stack: [u16; CALL_STACK_SIZE as usize],
+    call_stack: Vec<u16>,
+    call_stack_size: usize,
     // Program counter of current task.
     pc: u16,
-    // Call-stack pointer
-    sp: u8,
     tasks: [Task; TASK_COUNT],
     pending_tasks: [Task; TASK_COUNT],
     needs_yield: bool,
     last_swap_time: Instant,
+    // 50 Hz ticks requested by display updates in current frame, if any.
+    pause_slices: Option<u16>,
+    // All 50 Hz ticks requested by display updates so far.
+    ticks: u64,
+    // Timing of the last `pace_frame` which waited.
+    pacing: Option<FramePacing>,
+    // Execution count of every opcode byte, when profiling.
+    opcode_counts: Option<Box<[u64; 256]>>,
+    // Bitset of executed instruction offsets in code segment of every part,
+    // when collecting coverage.
+    coverage: Option<BTreeMap<u16, Box<[u64; 1024]>>>,
 }
 
 impl Vm {
-    pub fn new() -> Self {
+    pub fn new(call_stack_size: usize) -> Self {
         let mut vm = Self {
             regs: [0; 256],
-            call_stack: [0; CALL_STACK_SIZE as usize],
+            call_stack: Vec::with_capacity(call_stack_size),
+            call_stack_size,
             pc: 0,
-            sp: 0,
             tasks: [Default::default(); TASK_COUNT],
             pending_tasks: [Default::default(); TASK_COUNT],
             needs_yield: false,
             last_swap_time: Instant::now(),
+            pause_slices: None,
+            ticks: 0,
+            pacing: None,
+            opcode_counts: None,
+            coverage: None,
         };
 
         vm.regs[reg_id::RANDOM_SEED] = rand::thread_rng().gen();
@@ -78,6 +111,148 @@ impl Vm {
     pub fn sync_music(&mut self, val: u16) {
         self.regs[reg_id::MUSIC_SYNC] = val as i16;
     }
+
+    // Last value written by music sync event.
+    pub fn music_sync(&self) -> u16 {
+        self.regs[reg_id::MUSIC_SYNC] as u16
+    }
+
+    pub fn regs(&self) -> &[i16; 256] {
+        &self.regs
+    }
+
+    // Returns IDs and program counters of tasks which are not halted.
+    pub fn active_tasks(&self) -> impl Iterator<Item = (usize, u16)> + '_ {
+        self.tasks
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.pc != HALT_PC)
+            .map(|(id, t)| (id, t.pc))
+    }
+
+    // Whether no task is running nor about to be started, which leaves the
+    // game idle until input or a part change. Happens when some sequences
+    // (e.g. the intro) end.
+    pub fn all_tasks_halted(&self) -> bool {
+        self.tasks.iter().all(|t| t.pc == HALT_PC)
+            && self
+                .pending_tasks
+                .iter()
+                .all(|t| t.pc == HALT_PC || t.pc == PRE_HALT_PC)
+    }
+
+    // Freezes or unfreezes given task until a script changes it again, for
+    // debugging. Like `op_change_tasks`, it's applied to pending state too,
+    // so it's kept by `stage_tasks`.
+    pub fn set_task_frozen(&mut self, id: usize, frozen: bool) {
+        let id = check_task_id(id);
+        self.tasks[id].frozen = frozen;
+        self.pending_tasks[id].frozen = frozen;
+    }
+
+    pub fn is_task_frozen(&self, id: usize) -> bool {
+        self.tasks[check_task_id(id)].frozen
+    }
+
+    // Freezes all tasks except given one.
+    pub fn solo_task(&mut self, id: usize) {
+        check_task_id(id);
+        for other in 0..TASK_COUNT {
+            self.set_task_frozen(other, other != id);
+        }
+    }
+
+    pub fn unfreeze_all_tasks(&mut self) {
+        for id in 0..TASK_COUNT {
+            self.set_task_frozen(id, false);
+        }
+    }
+
+    pub fn enable_opcode_profile(&mut self) {
+        self.opcode_counts = Some(Box::new([0; 256]));
+    }
+
+    // Returns counts of executed opcodes, most frequent first. Shape opcodes
+    // are counted per operand encoding, see `opcode_mode`. Empty unless
+    // profiling is enabled.
+    pub fn opcode_profile(&self) -> Vec<(u8, u64)> {
+        let counts = match &self.opcode_counts {
+            Some(counts) => counts,
+            None => return Vec::new(),
+        };
+
+        let mut modes = [0; 256];
+        for (opcode, count) in (0..=255u8).zip(counts.iter()) {
+            modes[usize::from(opcode_mode(opcode))] += count;
+        }
+        let mut hist: Vec<(u8, u64)> = (0..=255u8)
+            .zip(modes.iter().copied())
+            .filter(|(_, count)| *count != 0)
+            .collect();
+        hist.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        hist
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(BTreeMap::new());
+    }
+
+    fn mark_covered(&mut self, part: u16, pc: u16) {
+        if let Some(coverage) = &mut self.coverage {
+            let bits = coverage.entry(part).or_insert_with(|| Box::new([0; 1024]));
+            bits[usize::from(pc / 64)] |= 1 << (pc % 64);
+        }
+    }
+
+    // Writes offsets of executed instructions, one "part offset" pair per
+    // line, e.g. "16001 0x01A2".
+    pub fn write_coverage(&self, w: &mut impl std::io::Write) -> std::io::Result<()> {
+        let coverage = match &self.coverage {
+            Some(coverage) => coverage,
+            None => return Ok(()),
+        };
+        for (part, bits) in coverage {
+            for pc in 0..=u16::MAX {
+                if (bits[usize::from(pc / 64)] & (1 << (pc % 64))) != 0 {
+                    writeln!(w, "{} 0x{:04X}", part, pc)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Scripts derive random events from this register and advance it
+    // themselves, so resetting it while running repeats the same sequence.
+    pub fn set_random_seed(&mut self, seed: i16) {
+        self.regs[reg_id::RANDOM_SEED] = seed;
+    }
+
+    pub fn random_seed(&self) -> i16 {
+        self.regs[reg_id::RANDOM_SEED]
+    }
+
+    pub fn ticks(&self) -> u64 {
+        self.ticks
+    }
+
+    pub fn pacing(&self) -> Option<FramePacing> {
+        self.pacing
+    }
+
+    pub fn take_pause_slices(&mut self) -> u16 {
+        self.pause_slices.take().unwrap_or(0)
+    }
+
+    pub fn hash_state(&self, mut hash: u64) -> u64 {
+        for reg in self.regs.iter() {
+            hash = lockstep::fnv1a(hash, &reg.to_le_bytes());
+        }
+        for task in self.tasks.iter().chain(self.pending_tasks.iter()) {
+            hash = lockstep::fnv1a(hash, &task.pc.to_le_bytes());
+            hash = lockstep::fnv1a(hash, &[u8::from(task.frozen)]);
+        }
+        hash
+    }
 }
 
 #[derive(Default)]
@@ -88,6 +263,51 @@ pub struct Input {
     pub down: bool,
     pub up: bool,
     pub button: bool,
+    // Button changes between the last two `update_input` calls.
+    button_pressed: bool,
+    button_released: bool,
+    prev_button: bool,
+}
+
+// Copy of the input state as seen by the scripts on the next frame.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InputState {
+    pub last_char: Option<u8>,
+    pub right: bool,
+    pub left: bool,
+    pub down: bool,
+    pub up: bool,
+    pub button: bool,
+}
+
+impl Input {
+    pub fn set_state(&mut self, state: InputState) {
+        self.last_char = state.last_char;
+        self.right = state.right;
+        self.left = state.left;
+        self.down = state.down;
+        self.up = state.up;
+        self.button = state.button;
+    }
+
+    pub fn button_pressed(&self) -> bool {
+        self.button_pressed
+    }
+
+    pub fn button_released(&self) -> bool {
+        self.button_released
+    }
+
+    pub fn snapshot(&self) -> InputState {
+        InputState {
+            last_char: self.last_char,
+            right: self.right,
+            left: self.left,
+            down: self.down,
+            up: self.up,
+            button: self.button,
+        }
+    }
 }
 
 fn is_valid_keychar(c: u8) -> bool {
@@ -126,6 +346,10 @@ pub fn update_input(g: &mut Game) {
     regs[reg_id::HERO_POS_MASK] = mask.into();
     regs[reg_id::HERO_ACTION] = input.button.into();
     regs[reg_id::HERO_ACTION_POS_MASK] = (mask | (u8::from(input.button) << 7)).into();
+
+    input.button_pressed = input.button && !input.prev_button;
+    input.button_released = !input.button && input.prev_button;
+    input.prev_button = input.button;
 }
 
 fn fetch_u8(g: &mut Game) -> u8 {
@@ -229,19 +453,31 @@ fn op_shr_const(g: &mut Game) {
 }
 
 fn op_call(g: &mut Game) {
-    assert!(g.vm.sp < CALL_STACK_SIZE, "call-stack overflow");
     let new_pc = fetch_u16(g);
     log::trace!("br 0x{:04X}", new_pc);
-    g.vm.call_stack[usize::from(g.vm.sp)] = g.vm.pc;
+    if g.vm.call_stack.len() >= g.vm.call_stack_size {
+        log::error!("call-stack overflow at 0x{:04X}, halting task", g.vm.pc);
+        halt_current_task(g);
+        return;
+    }
+    g.vm.call_stack.push(g.vm.pc);
     g.vm.pc = new_pc;
-    g.vm.sp += 1;
 }
 
 fn op_ret(g: &mut Game) {
-    assert!(g.vm.sp > 0, "call-stack underflow");
     log::trace!("ret");
-    g.vm.sp -= 1;
-    g.vm.pc = g.vm.call_stack[usize::from(g.vm.sp)];
+    match g.vm.call_stack.pop() {
+        Some(pc) => g.vm.pc = pc,
+        None => {
+            log::error!("call-stack underflow at 0x{:04X}, halting task", g.vm.pc);
+            halt_current_task(g);
+        }
+    }
+}
+
+fn halt_current_task(g: &mut Game) {
+    g.vm.pc = HALT_PC;
+    g.vm.needs_yield = true;
 }
 
 fn op_jmp(g: &mut Game) {
@@ -302,17 +538,20 @@ fn op_cond_jmp(g: &mut Game) {
         _ => panic!("invalid condition in jump"),
     };
 
-    if var_id == 0x29 && (op & 0x80) != 0 && g.current_part == 16000 && g.bypass_protection {
+    if var_id == reg_id::PROTECTION_ENTERED
+        && (op & 0x80) != 0
+        && g.current_part == 16000
+        && g.bypass_protection
+    {
         log::info!("bypassing protection");
         test = true;
         // 4 symbols
-        g.vm.regs[0x29] = g.vm.regs[0x1E];
-        g.vm.regs[0x2A] = g.vm.regs[0x1F];
-        g.vm.regs[0x2B] = g.vm.regs[0x20];
-        g.vm.regs[0x2C] = g.vm.regs[0x21];
+        for i in 0..4 {
+            g.vm.regs[reg_id::PROTECTION_ENTERED + i] = g.vm.regs[reg_id::PROTECTION_EXPECTED + i];
+        }
         // counters
-        g.vm.regs[0x32] = 6;
-        g.vm.regs[0x64] = 20;
+        g.vm.regs[reg_id::PROTECTION_COUNTER_1] = 6;
+        g.vm.regs[reg_id::PROTECTION_COUNTER_2] = 20;
     }
 
     if test {
@@ -334,8 +573,7 @@ fn op_install_task(g: &mut Game) {
 
 fn op_remove_task(g: &mut Game) {
     log::trace!("halt");
-    g.vm.pc = HALT_PC;
-    g.vm.needs_yield = true;
+    halt_current_task(g);
 }
 
 fn op_yield_task(g: &mut Game) {
@@ -399,18 +637,78 @@ pub fn stage_tasks(g: &mut Game) {
     }
 }
 
+// Skips the rest of current part, continuing the story with the next one.
+// Does nothing in the final part and on password screens.
+pub fn skip_scene(g: &mut Game) {
+    if let Some(part) = crate::mem::next_story_part(g.current_part) {
+        g.next_part = Some(part);
+    }
+}
+
+// Registers used by the copy protection screens (part 16000). The player is
+// asked for 4 symbols from the code wheel, which the script compares with
+// the expected ones one by one. Bypassing makes the first comparison pass
+// with all symbols entered correctly and counters set to values of a
+// successful attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtectionState {
+    // Registers 0x1E..=0x21.
+    pub expected: [i16; 4],
+    // Registers 0x29..=0x2C.
+    pub entered: [i16; 4],
+    // Registers 0x32 and 0x64, set to 6 and 20 when bypassing.
+    pub counters: [i16; 2],
+    // Register 0x54, set to 0x81 on entering part 16000 with the bypass.
+    pub flags: i16,
+    // Whether the comparison of entered symbols is forced to pass.
+    pub bypass: bool,
+    // Whether protection screens are running.
+    pub active: bool,
+}
+
+pub fn protection_state(g: &Game) -> ProtectionState {
+    let regs = &g.vm.regs;
+    let four = |first: usize| {
+        [
+            regs[first],
+            regs[first + 1],
+            regs[first + 2],
+            regs[first + 3],
+        ]
+    };
+    ProtectionState {
+        expected: four(reg_id::PROTECTION_EXPECTED),
+        entered: four(reg_id::PROTECTION_ENTERED),
+        counters: [
+            regs[reg_id::PROTECTION_COUNTER_1],
+            regs[reg_id::PROTECTION_COUNTER_2],
+        ],
+        flags: regs[reg_id::PROTECTION_FLAGS],
+        bypass: g.bypass_protection,
+        active: g.current_part == 16000,
+    }
+}
+
 pub fn restart_at(g: &mut Game, part: u16, pos: i16) {
     sfx::stop_sound_and_music(g);
+    crate::host::begin_scene_fade(g);
 
     g.vm.regs[0xE4] = 20;
-    if part == 16000 {
-        g.vm.regs[0x54] = 0x81;
+    // Part of the protection bypass, like registers preset in `Vm::new`.
+    // Without the bypass it's left for the protection scripts to set.
+    if part == 16000 && g.bypass_protection {
+        g.vm.regs[reg_id::PROTECTION_FLAGS] = 0x81;
     }
 
-    mem::setup_part(g, part);
+    let loaded = mem::setup_part(g, part);
 
     g.vm.tasks = [Task::default(); TASK_COUNT];
     g.vm.pending_tasks = [Task::default(); TASK_COUNT];
+    if let Err(e) = loaded {
+        log::error!("unable to start part {}: {}", part, e);
+        g.host.request_quit();
+        return;
+    }
 
     g.vm.tasks[0].pc = 0;
     g.screen_num = None;
@@ -419,8 +717,10 @@ pub fn restart_at(g: &mut Game, part: u16, pos: i16) {
         g.vm.regs[0] = pos;
     }
 
-    if g.video.needs_pal_fixup() && part == 16009 {
-        video::load_pal_mem(g, 5);
+    if g.video.needs_pal_fixup() {
+        if let Some(&(_, pal)) = PART_START_PALS.iter().find(|(p, _)| *p == part) {
+            video::load_pal_mem(g, pal);
+        }
     }
 
     g.vm.last_swap_time = Instant::now();
@@ -433,16 +733,72 @@ pub fn run_tasks(g: &mut Game) {
         }
 
         g.vm.pc = g.vm.tasks[id].pc;
-        g.vm.sp = 0;
+        g.vm.call_stack.clear();
         g.vm.needs_yield = false;
         execute_task(g);
         g.vm.tasks[id].pc = g.vm.pc;
     }
 }
 
+// Shape opcodes carry operands in their low bits, so the same encoding of
+// operands maps to many opcodes. Returns the lowest of them.
+fn opcode_mode(opcode: u8) -> u8 {
+    if (opcode & 0x80) != 0 {
+        // Offset bits.
+        0x80
+    } else if (opcode & 0x60) == 0x60 {
+        // Ninth bit of an immediate X.
+        opcode & !0x10
+    } else {
+        opcode
+    }
+}
+
+// Names shape opcodes after the encoding of their operands, see
+// `op_draw_shape`: "i16", "i9" and "i8" are immediates, "reg" a register and
+// "def" the default zoom, "seg2" also drawing from the second segment.
+pub fn opcode_name(opcode: u8) -> String {
+    const NAMES: [&str; 0x1B] = [
+        "movi", "mov", "add", "addi", "br", "ret", "yield", "b", "task", "bif", "bcond", "gpal",
+        "xtask", "fb_sel", "fb_fill", "fb_copy", "swap", "halt", "gstr", "sub", "andi", "ori",
+        "shli", "shri", "snd", "res", "music",
+    ];
+    if (opcode & 0x80) != 0 {
+        return "shape (short)".to_string();
+    }
+    if (opcode & 0x40) == 0 {
+        return NAMES
+            .get(usize::from(opcode))
+            .copied()
+            .unwrap_or("invalid")
+            .to_string();
+    }
+    let x = match opcode & 0x30 {
+        0x00 => "i16",
+        0x10 => "reg",
+        _ => "i9",
+    };
+    let y = match opcode & 0x0C {
+        0x00 => "i16",
+        0x04 => "reg",
+        _ => "i8",
+    };
+    let zoom = match opcode & 0x03 {
+        0x00 => "def",
+        0x01 => "reg",
+        0x02 => "i8",
+        _ => "seg2",
+    };
+    format!("shape x={} y={} zoom={}", x, y, zoom)
+}
+
 fn execute_task(g: &mut Game) {
     while !g.vm.needs_yield {
+        g.vm.mark_covered(g.current_part, g.vm.pc);
         let opcode = fetch_u8(g);
+        if let Some(counts) = &mut g.vm.opcode_counts {
+            counts[usize::from(opcode)] += 1;
+        }
         if (opcode & 0xC0) != 0 {
             op_draw_shape(g, opcode);
         } else {
@@ -579,7 +935,7 @@ fn op_change_pal(g: &mut Game) {
     log::trace!("gpal {}, {}", num, _dummy);
 
     let skip_change =
-        g.video.needs_pal_fixup() && g.current_part == 16001 && (num == 10 || num == 16);
+        g.video.needs_pal_fixup() && SKIPPED_PAL_CHANGES.contains(&(g.current_part, num));
 
     if !skip_change {
         g.next_pal = Some(num);
@@ -597,15 +953,48 @@ fn op_play_sound(g: &mut Game) {
     play_sound_shim(g, resource, freq, volume, channel);
 }
 
+// Channels past the last one wrap around, like with the original 4 channels.
 fn play_sound_shim(g: &mut Game, resource: u16, freq: u8, volume: u8, channel: u8) {
+    let channel = channel % g.host.sound_channel_count();
     if volume == 0 {
         sfx::stop_sound(g, channel);
     } else {
         let volume = std::cmp::min(volume, 0x3F);
-        if let Some(address) = mem::address_of_entry(&g.mem, resource) {
-            let freq = crate::data::FREQUENCY_TABLE[usize::from(freq)];
-            sfx::play_sound(g, channel & 3, address, freq, volume);
+        let freq = crate::data::FREQUENCY_TABLE[usize::from(freq)];
+        sfx::play_sound(g, channel, resource, freq, volume);
+        crate::captions::on_sound(g, resource);
+    }
+}
+
+// Loads given resource if it is of expected kind, for debug commands.
+fn load_debug_resource(g: &mut Game, resource: u16, kind: u8) -> bool {
+    match mem::kind_of_entry(&g.mem, resource) {
+        Some(k) if k == kind => {
+            mem::load_entry(g, resource);
+            true
         }
+        Some(k) => {
+            log::warn!("resource {} is of kind {}, not {}", resource, k, kind);
+            false
+        }
+        None => {
+            log::warn!("no resource {}", resource);
+            false
+        }
+    }
+}
+
+// Plays sound like the script would, without reaching the scene using it.
+pub fn debug_play_sound(g: &mut Game, resource: u16, freq: u8, volume: u8, channel: u8) {
+    if load_debug_resource(g, resource, mem::entry_kind::SOUND) {
+        let freq = std::cmp::min(usize::from(freq), crate::data::FREQUENCY_TABLE.len() - 1);
+        play_sound_shim(g, resource, freq as u8, volume, channel);
+    }
+}
+
+pub fn debug_play_music(g: &mut Game, resource: u16) {
+    if load_debug_resource(g, resource, mem::entry_kind::MUSIC) {
+        sfx::seek(g, resource, 0, 0);
     }
 }
 
@@ -642,6 +1031,7 @@ fn op_update_display(g: &mut Game) {
     log::trace!("swap {}", page);
 
     let fb = video::swap_pages(&mut g.video, page);
+    video::record_frame(&mut g.video, fb);
 
     if let Some(num) = g.next_pal.take() {
         video::load_pal_mem(g, num);
@@ -649,27 +1039,406 @@ fn op_update_display(g: &mut Game) {
 
     crate::host::display_surface(g, fb);
 
+    let slices = std::cmp::max(g.vm.regs[reg_id::PAUSE_SLICES], 0) as u16;
+    g.vm.pause_slices = Some(g.vm.pause_slices.unwrap_or(0).saturating_add(slices));
+    g.vm.ticks += u64::from(slices);
+    g.vm.regs[0xF7] = 0;
+}
+
+// Timing of a paced frame, for diagnosing stutter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FramePacing {
+    // Time the frame should take, given the requested ticks and speed.
+    pub target: Duration,
+    // Time since previous pacing, before waiting.
+    pub elapsed: Duration,
+    // Time spent sleeping to reach the target.
+    pub slept: Duration,
+}
+
+impl FramePacing {
+    // How much longer the frame took than it should have.
+    pub fn behind(&self) -> Duration {
+        self.elapsed.saturating_sub(self.target)
+    }
+}
+
+// Waits the number of 50 Hz ticks requested by display updates of the last
+// frame, feeding the music meanwhile. Kept out of `run_frame` so the game
+// logic doesn't depend on wall-clock time.
+//
+// Sleeping is measured from the end of previous pacing, so time spent
+// blocked in a vsync-locked present counts towards the tick. With vsync the
+// sleep also ends a refresh interval early, leaving the last wait to the
+// next present so the two don't add up. Game logic stays at 50 Hz
+// regardless of refresh rate.
+//
+// Ticks are stretched when running below normal speed. Music is muted then,
+// as it's driven by the audio device clock and can't slow down with the game.
+//
+// With `--reference-timing` frames are paced by `pace_frame_vbl` instead.
+pub fn pace_frame(g: &mut Game) {
+    let slices = match g.vm.pause_slices.take() {
+        Some(slices) => slices,
+        None => return,
+    };
+
     const HZ: i32 = 50;
-    let mut delay = g.vm.last_swap_time.elapsed().as_millis() as i32;
-    for _ in 0..g.vm.regs[reg_id::PAUSE_SLICES] {
-        crate::host::produce_music(g);
-        delay -= 1000 / HZ;
+    let slowed = g.speed_multiplier < 1.0;
+    let tick_ms = ((1000 / HZ) as f32 / g.speed_multiplier) as i32;
+    if g.reference_timing {
+        pace_frame_vbl(g, slices, Duration::from_millis(tick_ms as u64), slowed);
+        return;
+    }
+    let elapsed = g.vm.last_swap_time.elapsed();
+    let mut delay = elapsed.as_millis() as i32;
+    let mut slept = Duration::default();
+    for _ in 0..slices {
+        if !slowed {
+            crate::host::produce_music(g);
+        }
+        delay -= tick_ms;
         if delay < 0 {
-            std::thread::sleep(Duration::from_millis(-delay as u64));
+            slept += sleep_until_present(g, Duration::from_millis(-delay as u64));
             delay = 0;
         }
     }
 
+    g.vm.pacing = Some(FramePacing {
+        target: Duration::from_millis(u64::from(slices) * tick_ms as u64),
+        elapsed,
+        slept,
+    });
     g.vm.last_swap_time = Instant::now();
-    g.vm.regs[0xF7] = 0;
+}
+
+// Paces like the original, which counted vertical blanks at 50 Hz and
+// waited until `PAUSE_SLICES` of them passed since the last swap. Unlike
+// the default pacing, frames end on a fixed 50 Hz grid: `last_swap_time`
+// holds the last counted blank rather than when waiting ended. A frame
+// finishing between blanks waits for the next one, and blanks missed while
+// running late count towards the frame without being made up later.
+fn pace_frame_vbl(g: &mut Game, slices: u16, tick: Duration, slowed: bool) {
+    for _ in 0..slices {
+        if !slowed {
+            crate::host::produce_music(g);
+        }
+    }
+
+    let elapsed = g.vm.last_swap_time.elapsed();
+    let passed = (elapsed.as_nanos() / tick.as_nanos()) as u32;
+    let target = tick * u32::from(slices);
+    let mut slept = Duration::default();
+    if passed < u32::from(slices) {
+        slept = sleep_until_present(g, target - elapsed);
+        g.vm.last_swap_time += target;
+    } else {
+        g.vm.last_swap_time += tick * passed;
+    }
+
+    g.vm.pacing = Some(FramePacing {
+        target,
+        elapsed,
+        slept,
+    });
+}
+
+// Sleeps for `wait`, less a refresh interval when the next present waits
+// for vertical blank anyway. Returns the time slept.
+fn sleep_until_present(g: &Game, wait: Duration) -> Duration {
+    let wait = match g.host.refresh_interval() {
+        Some(interval) => wait.saturating_sub(interval),
+        None => wait,
+    };
+    if g.precise_timing {
+        sleep_precise(wait);
+    } else {
+        std::thread::sleep(wait);
+    }
+    wait
+}
+
+// Sleeps until shortly before the deadline and busy-waits the rest, as
+// `thread::sleep` often oversleeps by a millisecond or more. Smoother, but
+// keeps a CPU core busy for the spin of every frame.
+fn sleep_precise(duration: Duration) {
+    const SPIN: Duration = Duration::from_millis(1);
+    let deadline = Instant::now() + duration;
+    if duration > SPIN {
+        std::thread::sleep(duration - SPIN);
+    }
+    while Instant::now() < deadline {
+        std::hint::spin_loop();
+    }
+}
+
+// Forces screen number register as if set by the script and applies the
+// palette fixups of the screen change, to probe screens while debugging.
+pub fn force_screen(g: &mut Game, screen: i16) {
+    g.vm.regs[reg_id::SCREEN_NUM] = screen;
+    g.screen_num = Some(screen);
+    fixup_pal_after_change_screen(g, screen);
 }
 
 fn fixup_pal_after_change_screen(g: &mut Game, screen: i16) {
-    if let Some(pal) = match (g.current_part, screen) {
-        (16004, 0x47) => Some(8),
-        (16006, 0x4A) => Some(1),
-        _ => None,
-    } {
+    if let Some(&(_, _, pal)) = SCREEN_PAL_FIXUPS
+        .iter()
+        .find(|(part, s, _)| *part == g.current_part && *s == screen)
+    {
         video::load_pal_mem(g, pal);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn pause_slices_saturate_over_many_display_updates() {
+        // movi @FF, 0x7FFF; swap 0xFE three times; yield
+        let code = [
+            0x00, 0xFF, 0x7F, 0xFF, 0x10, 0xFE, 0x10, 0xFE, 0x10, 0xFE, 0x06,
+        ];
+        let mut g = testing::game("pause_slices_saturate_over_many_display_updates", &code);
+
+        crate::run_frame(&mut g);
+
+        assert_eq!(g.vm.take_pause_slices(), u16::MAX);
+        assert_eq!(g.vm.ticks(), 3 * 0x7FFF);
+    }
+
+    #[test]
+    fn vsync_leaves_last_refresh_to_present() {
+        // movi @FF, 1; swap 0xFF; yield; b 0x0000
+        let code = [0x00, 0xFF, 0x00, 0x01, 0x10, 0xFF, 0x06, 0x07, 0x00, 0x00];
+        let host = testing::TestHost::default();
+        host.refresh_interval.set(Some(Duration::from_millis(40)));
+        let mut g = testing::game_with_host("vsync_leaves_last_refresh_to_present", &code, host);
+
+        crate::run_frame(&mut g);
+        g.vm.last_swap_time = Instant::now();
+        pace_frame(&mut g);
+        let pacing = g.vm.pacing().unwrap();
+        assert_eq!(pacing.target, Duration::from_millis(20));
+        assert_eq!(pacing.slept, Duration::from_secs(0));
+
+        let mut g = testing::game("vsync_leaves_last_refresh_to_present_off", &code);
+        crate::run_frame(&mut g);
+        g.vm.last_swap_time = Instant::now();
+        pace_frame(&mut g);
+        assert!(g.vm.pacing().unwrap().slept > Duration::from_millis(10));
+    }
+
+    #[test]
+    fn button_edges_fire_once() {
+        let mut g = testing::game("button_edges_fire_once", &[0x06, 0x07, 0x00, 0x00]);
+        let mut edges = Vec::new();
+        for &button in &[false, true, true, true, false, false] {
+            g.input.button = button;
+            update_input(&mut g);
+            assert_eq!(g.vm.regs[reg_id::HERO_ACTION], i16::from(button));
+            edges.push((g.input.button_pressed(), g.input.button_released()));
+        }
+        assert_eq!(
+            edges,
+            [
+                (false, false),
+                (true, false),
+                (false, false),
+                (false, false),
+                (false, true),
+                (false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn protection_flags_preset_follows_bypass() {
+        for &bypass in &[true, false] {
+            let name = format!("protection_flags_preset_follows_bypass_{}", bypass);
+            let m = testing::memory(
+                &name,
+                &[
+                    (0x14, mem::entry_kind::PALETTE, &[0; 2048]),
+                    (0x15, mem::entry_kind::BYTECODE, &[0x06, 0x07, 0x00, 0x00]),
+                    (0x16, mem::entry_kind::SHAPE, &[0; 16]),
+                ],
+            );
+            let mut g = Game::new(Box::new(crate::NullHost::default()), m);
+            g.bypass_protection = bypass;
+
+            restart_at(&mut g, 16000, -1);
+
+            assert!(mem::is_part_ready(&g.mem, 16000));
+            let flags = protection_state(&g).flags;
+            assert_eq!(flags, if bypass { 0x81 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn coverage_lists_executed_offsets() {
+        let code = [
+            0x00, 0x00, 0x00, 0x01, // movi @00, 1
+            0x07, 0x00, 0x08, // b 0x0008
+            0x00, // never reached
+            0x06, // yield
+            0x07, 0x00, 0x08, // b 0x0008
+        ];
+        let mut g = testing::game("coverage_lists_executed_offsets", &code);
+        g.vm.enable_coverage();
+
+        run_tasks(&mut g);
+        run_tasks(&mut g);
+
+        let mut out = Vec::new();
+        g.vm.write_coverage(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "16001 0x0000\n16001 0x0004\n16001 0x0008\n16001 0x0009\n"
+        );
+    }
+
+    #[test]
+    fn opcode_profile_groups_shapes_by_operand_encoding() {
+        let mut vm = Vm::new(DEFAULT_CALL_STACK_SIZE);
+        assert!(vm.opcode_profile().is_empty());
+
+        vm.enable_opcode_profile();
+        let counts = vm.opcode_counts.as_mut().unwrap();
+        counts[0x06] = 3;
+        counts[0x80] = 1;
+        counts[0xFF] = 1;
+        counts[0x60] = 2;
+        counts[0x70] = 2;
+        counts[0x50] = 1;
+
+        assert_eq!(
+            vm.opcode_profile(),
+            vec![(0x60, 4), (0x06, 3), (0x80, 2), (0x50, 1)]
+        );
+        assert_eq!(opcode_name(0x06), "yield");
+        assert_eq!(opcode_name(0x60), "shape x=i9 y=i16 zoom=def");
+        assert_eq!(opcode_name(0x57), "shape x=reg y=reg zoom=seg2");
+        assert_eq!(opcode_name(0x80), "shape (short)");
+    }
+
+    #[test]
+    fn reset_seed_repeats_random_branch() {
+        let code = [
+            0x0A, 0x42, 0x3C, 0x00, 0x00, 0x00, 0x0E, // bgt @3C, 0, 0x000E
+            0x00, 0x01, 0x00, 0x01, // movi @01, 1
+            0x07, 0x00, 0x12, // b 0x0012
+            0x00, 0x01, 0x00, 0x02, // movi @01, 2
+            0x03, 0x3C, 0x11, 0x11, // addi @3C, 0x1111
+            0x06, // yield
+        ];
+        let mut g = testing::game("reset_seed_repeats_random_branch", &code);
+        let run = |g: &mut Game, seed| {
+            g.vm.set_random_seed(seed);
+            assert_eq!(g.vm.random_seed(), seed);
+            g.vm.tasks[0].pc = 0;
+            run_tasks(g);
+            (g.vm.regs[1], g.vm.random_seed())
+        };
+
+        let first = run(&mut g, 5);
+        assert_eq!(first, (2, 0x1116));
+        assert_eq!(run(&mut g, 5), first);
+        assert_eq!(run(&mut g, -5), (1, 0x110C));
+    }
+
+    #[test]
+    fn frozen_task_is_skipped() {
+        // Task 0 starts task 1 at 0x000C, each increments its own variable
+        // every frame.
+        let code = [
+            0x08, 0x01, 0x00, 0x0C, // task 1, 0x000C
+            0x03, 0x10, 0x00, 0x01, // addi @10, 1
+            0x06, 0x07, 0x00, 0x04, // yield; b 0x0004
+            0x03, 0x11, 0x00, 0x01, // addi @11, 1
+            0x06, 0x07, 0x00, 0x0C, // yield; b 0x000C
+        ];
+        let mut g = testing::game("frozen_task_is_skipped", &code);
+        crate::run_frame(&mut g);
+        crate::run_frame(&mut g);
+        assert_eq!((g.vm.regs()[0x10], g.vm.regs()[0x11]), (2, 1));
+
+        g.vm.set_task_frozen(1, true);
+        crate::run_frame(&mut g);
+        crate::run_frame(&mut g);
+        assert!(g.vm.is_task_frozen(1));
+        assert_eq!((g.vm.regs()[0x10], g.vm.regs()[0x11]), (4, 1));
+
+        g.vm.solo_task(1);
+        crate::run_frame(&mut g);
+        assert_eq!((g.vm.regs()[0x10], g.vm.regs()[0x11]), (4, 2));
+
+        g.vm.unfreeze_all_tasks();
+        crate::run_frame(&mut g);
+        assert_eq!((g.vm.regs()[0x10], g.vm.regs()[0x11]), (5, 3));
+    }
+
+    #[test]
+    fn screen_palette_fixups_apply() {
+        let mut g = testing::game("screen_palette_fixups_apply", &[0x06]);
+        for &(part, screen, pal) in SCREEN_PAL_FIXUPS {
+            // First color of the fixup palette is red.
+            let color = g.mem.seg_video_pal() + usize::from(pal) * 32;
+            g.mem.data[color..color + 2].copy_from_slice(&[0x0F, 0x00]);
+            g.current_part = part;
+
+            fixup_pal_after_change_screen(&mut g, screen + 1);
+            assert_ne!(g.video.current_pal_num(), Some(pal));
+
+            fixup_pal_after_change_screen(&mut g, screen);
+            assert_eq!(g.video.current_pal_num(), Some(pal));
+            assert_eq!(g.video.rndr.palette()[0].r, 0xFF);
+        }
+        assert_eq!(SCREEN_PAL_FIXUPS, &[(16004, 0x47, 8), (16006, 0x4A, 1)]);
+    }
+
+    #[test]
+    fn forced_palette_ignores_palette_changes() {
+        // gpal 3; swap 0xFF; yield; b 0x0000
+        let code = [0x0B, 0x03, 0x00, 0x10, 0xFF, 0x06, 0x07, 0x00, 0x00];
+        let mut g = testing::game("forced_palette_ignores_palette_changes", &code);
+        crate::run_frame(&mut g);
+        assert_eq!(g.video.current_pal_num(), Some(3));
+
+        g.video.set_forced_pal_num(Some(5));
+        crate::run_frame(&mut g);
+        assert_eq!(g.video.current_pal_num(), Some(5));
+
+        g.video.set_forced_pal_num(None);
+        crate::run_frame(&mut g);
+        assert_eq!(g.video.current_pal_num(), Some(3));
+    }
+
+    #[test]
+    fn smaller_call_stack_overflows_sooner() {
+        // addi @10, 1; call 0x0000
+        let code = [0x03, 0x10, 0x00, 0x01, 0x04, 0x00, 0x00];
+        for &(size, calls) in &[(4, 5), (DEFAULT_CALL_STACK_SIZE, 65)] {
+            let mut g = testing::game("smaller_call_stack_overflows_sooner", &code);
+            g.vm = Vm::new(size);
+            restart_at(&mut g, crate::INTRO_PART, -1);
+
+            crate::run_frame(&mut g);
+            assert_eq!(g.vm.regs()[0x10], calls);
+            assert_eq!(g.vm.active_tasks().count(), 0);
+        }
+    }
+
+    #[test]
+    fn forcing_screen_loads_fixup_palette() {
+        let mut g = testing::game("forcing_screen_loads_fixup_palette", &[0x06]);
+        g.current_part = 16004;
+
+        force_screen(&mut g, 0x47);
+
+        assert_eq!(g.vm.regs()[reg_id::SCREEN_NUM], 0x47);
+        assert_eq!(g.screen_num, Some(0x47));
+        assert_eq!(g.video.current_pal_num(), Some(8));
+    }
+}
@@ -10,6 +10,23 @@ pub struct Player {
     samples_left: u16,
     channels: [Channel; 4],
     track: Track,
+    // Fraction of the opposite side mixed into each output channel, 0 keeps
+    // the original hard panning and 0.5 is mono. See `set_stereo_width`.
+    cross_mix: f32,
+    // Output silence without advancing the track, see `set_music_paused`.
+    paused: bool,
+    // Play samples like Paula, see `set_amiga_audio`.
+    amiga: bool,
+    // Last output of the low-pass filter, for each side.
+    filter: [f32; 2],
+    // Last halved input of `nr`, for each side.
+    nr_prev: [i16; 2],
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ChannelStatus {
+    pub active: bool,
+    pub volume: u16,
 }
 
 #[derive(Default)]
@@ -27,7 +44,6 @@ struct Track {
     address: usize,
     cur_pos: u16,
     cur_order: u8,
-    #[allow(dead_code)]
     num_order: u16,
     order_table: TrackOrderTable,
     samples: [Instrument; 15],
@@ -58,7 +74,8 @@ pub fn seek(g: &mut Game, res_num: u16, delay: u16, cur_order: u8) {
         };
 
     let data = &g.mem.data[address..];
-    let num_order = BE::read_u16(&data[address + 0x3E..]);
+    let num_order = BE::read_u16(&data[0x3E..]);
+    debug_assert!(num_order <= 0x80, "invalid number of orders {}", num_order);
 
     let mut order_table = TrackOrderTable::default();
     order_table.0[..0x80].clone_from_slice(&data[64..(0x80 + 64)]);
@@ -84,16 +101,57 @@ pub fn seek(g: &mut Game, res_num: u16, delay: u16, cur_order: u8) {
     g.music.channels = Default::default();
 }
 
+// Starts music from given resource at given order position. A `delay` of 0
+// means the tempo stored in the track header is used, like the script opcode.
+// Orders past the ones listed in the header are rejected, leaving current
+// music as it was.
+pub fn play_music(g: &mut Game, res_num: u16, delay: u16, order: u8) {
+    let num_order = mem::address_of_entry_with_kind(&g.mem, res_num, mem::entry_kind::MUSIC)
+        .map(|address| BE::read_u16(&g.mem.data[address + 0x3E..]).min(0x80));
+    if let Some(num_order) = num_order {
+        if u16::from(order) >= num_order {
+            log::warn!(
+                "invalid music order {} for {} resource with {} orders",
+                order,
+                res_num,
+                num_order
+            );
+            return;
+        }
+    }
+    seek(g, res_num, delay, order);
+}
+
+pub fn stop_music(g: &mut Game) {
+    g.music.set_delay(0);
+}
+
+// Pausing keeps the track at current order and position, so it continues
+// from there when resumed. No sync events are sent meanwhile. Sound effects
+// aren't affected.
+pub fn set_music_paused(g: &mut Game, paused: bool) {
+    g.music.paused = paused;
+}
+
 fn prepare_instruments(g: &Game, data: &[u8]) -> [Instrument; 15] {
     let mut samples = [Instrument::default(); 15];
     for i in 0..15 {
         let ins = &mut samples[i];
         let res_num = BE::read_u16(&data[i * 4..]);
-        if res_num != 0 {
-            ins.volume = BE::read_u16(&data[i * 4 + 2..]);
-            ins.address =
-                mem::address_of_entry_with_kind(&g.mem, res_num, crate::mem::entry_kind::SOUND)
-                    .expect("error loading instrument");
+        if res_num == 0 {
+            continue;
+        }
+        // Notes of an instrument without address are skipped.
+        match mem::address_of_entry_with_kind(&g.mem, res_num, crate::mem::entry_kind::SOUND) {
+            Some(address) => {
+                ins.volume = BE::read_u16(&data[i * 4 + 2..]);
+                ins.address = address;
+            }
+            None => log::warn!(
+                "unable to load instrument {} from {} resource",
+                i + 1,
+                res_num
+            ),
         }
     }
     samples
@@ -103,11 +161,18 @@ fn cvt_delay(delay: u16) -> u16 {
     (u32::from(delay) * 60 / 7050) as u16
 }
 
-pub fn mix_samples(g: &mut Game, mut out: &mut [i16]) {
-    assert!(g.music.delay != 0);
+// Produces silence when no music is playing or it's paused.
+pub fn mix_samples(g: &mut Game, out: &mut [i16]) {
+    if g.music.delay == 0 || g.music.paused {
+        for sample in out.iter_mut() {
+            *sample = 0;
+        }
+        return;
+    }
 
     let mut len = (out.len() / 2) as u16;
     let samples_per_tick = HOST_RATE / (1000 / g.music.delay);
+    let mut pos = 0;
     while len != 0 {
         if g.music.samples_left == 0 {
             process_events(g);
@@ -120,32 +185,74 @@ pub fn mix_samples(g: &mut Game, mut out: &mut [i16]) {
 
         for i in 0..count {
             let sample = mix_channel(g, 0, 0);
-            let sample = mix_channel(g, 3, sample);
-            out[usize::from(i * 2)] = i16::from(sample) * 256;
+            let left = mix_channel(g, 3, sample);
 
             let sample = mix_channel(g, 1, 0);
-            let sample = mix_channel(g, 2, sample);
-            out[usize::from(i * 2 + 1)] = i16::from(sample) * 256;
+            let right = mix_channel(g, 2, sample);
+
+            let (left, right) = blend_stereo(g.music.cross_mix, left, right);
+            let (left, right) = if g.music.amiga {
+                amiga_filter(&mut g.music.filter, left, right)
+            } else {
+                (left, right)
+            };
+            out[pos + usize::from(i * 2)] = left;
+            out[pos + usize::from(i * 2 + 1)] = right;
         }
 
-        out = &mut out[usize::from(count * 2)..];
+        pos += usize::from(count * 2);
     }
 
-    nr(out)
+    nr(&mut g.music.nr_prev, out)
 }
 
-fn nr(out: &mut [i16]) {
-    let mut prev_l = 0;
-    let mut prev_r = 0;
+fn blend_stereo(cross_mix: f32, left: i8, right: i8) -> (i16, i16) {
+    let (left, right) = (i16::from(left) * 256, i16::from(right) * 256);
+    if cross_mix == 0.0 {
+        return (left, right);
+    }
+    let (l, r) = (f32::from(left), f32::from(right));
+    (
+        (l * (1.0 - cross_mix) + r * cross_mix) as i16,
+        (r * (1.0 - cross_mix) + l * cross_mix) as i16,
+    )
+}
+
+// Approximates the fixed RC low-pass filter of the Amiga 500 audio output,
+// a single pole at about 4.9 kHz.
+fn amiga_filter(state: &mut [f32; 2], left: i16, right: i16) -> (i16, i16) {
+    const CUTOFF: f32 = 4900.0;
+    let alpha = 1.0 - (-2.0 * std::f32::consts::PI * CUTOFF / f32::from(HOST_RATE)).exp();
+    for (y, x) in state.iter_mut().zip([left, right]) {
+        *y += alpha * (f32::from(x) - *y);
+    }
+    (state[0] as i16, state[1] as i16)
+}
+
+// Advances the music by exactly `frames` stereo frames without going through
+// the host ring buffer. Useful to drive the mixer deterministically.
+pub fn render(g: &mut Game, frames: usize) -> Vec<i16> {
+    let mut out = vec![0; frames * 2];
+    // `mix_samples` counts frames in `u16`.
+    for chunk in out.chunks_mut(usize::from(u16::MAX) & !1) {
+        mix_samples(g, chunk);
+    }
+    out
+}
+
+// Averages every sample with the previous one of the same side. `prev` is
+// kept between calls, so there are no clicks at buffer boundaries.
+fn nr(prev: &mut [i16; 2], out: &mut [i16]) {
+    let [prev_l, prev_r] = prev;
 
     for pair in out.chunks_exact_mut(2) {
         let l = pair[0] >> 1;
-        pair[0] = l.wrapping_add(prev_l);
-        prev_l = l;
+        pair[0] = l.wrapping_add(*prev_l);
+        *prev_l = l;
 
         let r = pair[1] >> 1;
-        pair[0] = r.wrapping_add(prev_r);
-        prev_r = r;
+        pair[1] = r.wrapping_add(*prev_r);
+        *prev_r = r;
     }
 }
 
@@ -171,9 +278,12 @@ fn mix_channel(g: &mut Game, ch: usize, in_sample: i8) -> i8 {
     }
 
     let data = &g.mem.data[ch.sample_address..];
-    let sample = ch
-        .pos
-        .interpolate(data[pos1 as usize] as i8, data[pos2 as usize] as i8);
+    let sample = if g.music.amiga {
+        i16::from(data[pos1 as usize] as i8)
+    } else {
+        ch.pos
+            .interpolate(data[pos1 as usize] as i8, data[pos2 as usize] as i8)
+    };
     let sample = i16::from(in_sample) + sample * (ch.volume as i16) / 64;
     std::cmp::max(-128, std::cmp::min(sample, 127)) as i8
 }
@@ -214,6 +324,15 @@ fn handle_pattern(g: &mut Game, channel: usize, address: usize) {
         return;
     }
 
+    if note1 != 0 && note1 != 0xFFFE && !(0x37..0x1000).contains(&note1) {
+        log::warn!(
+            "note period 0x{:04X} out of range on channel {}",
+            note1,
+            channel
+        );
+        return;
+    }
+
     let mut pattern = Pattern::default();
     let sample = note2 >> 12;
     if sample != 0 {
@@ -252,7 +371,6 @@ fn handle_pattern(g: &mut Game, channel: usize, address: usize) {
     if note1 == 0xFFFE {
         g.music.channels[channel].sample_len = 0;
     } else if note1 != 0 && pattern.sample_address != 0 {
-        assert!((0x37..0x1000).contains(&note1));
         // Convert Amiga period value to Hz.
         let freq = (7_159_092 / (u32::from(note1) * 2)) as u16;
         let ch = &mut g.music.channels[channel];
@@ -270,13 +388,92 @@ impl Player {
         self.delay = cvt_delay(delay);
     }
 
+    // Width of 1 keeps channels 0 and 3 on the left and 1 and 2 on the right,
+    // 0 mixes everything down to mono.
+    pub fn set_stereo_width(&mut self, width: f32) {
+        self.cross_mix = (1.0 - width.clamp(0.0, 1.0)) / 2.0;
+    }
+
+    // Paula played samples without interpolation, which gives the music its
+    // characteristic rough sound. When enabled, samples are held until the
+    // next one instead of interpolated, and the output goes through
+    // the Amiga's low-pass filter.
+    pub fn set_amiga_audio(&mut self, enabled: bool) {
+        self.amiga = enabled;
+        self.filter = [0.0; 2];
+    }
+
+    // Tick duration in milliseconds, 0 when no music is playing.
+    pub fn current_delay(&self) -> u16 {
+        self.delay
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     pub fn is_end_of_track(&self) -> bool {
         self.delay == 0
     }
+
+    // Patterns of the track in play order, as many as its header lists.
+    pub fn order_table(&self) -> &[u8] {
+        &self.track.order_table.0[..usize::from(self.track.num_order).min(0x80)]
+    }
+
+    // Index into `order_table` of the pattern being played.
+    pub fn current_order(&self) -> u8 {
+        self.track.cur_order
+    }
+
+    // Byte offset of the next row within current pattern.
+    pub fn current_pos(&self) -> u16 {
+        self.track.cur_pos
+    }
+
+    pub fn channel_status(&self) -> [ChannelStatus; 4] {
+        let mut status = [ChannelStatus::default(); 4];
+        for (s, ch) in status.iter_mut().zip(self.channels.iter()) {
+            s.active = ch.sample_len != 0;
+            s.volume = ch.volume;
+        }
+        status
+    }
 }
 
-pub fn play_sound(g: &mut Game, channel: u8, address: usize, freq: u16, volume: u8) {
-    let data = &g.mem.data[address..];
+// Adds a sound resource with `len` samples of reproducible noise to `m`,
+// returning its number, for exercising the mixer without game data. Sound
+// resources start with an 8-byte header:
+//
+//   u16 BE  length of the sample in words
+//   u16 BE  length of the loop in words, 0 for one-shot samples
+//   4 bytes unused
+//
+// followed by signed 8-bit samples. The loop, when present, starts right
+// after the sample, so its data follows.
+#[cfg(test)]
+pub fn synth_sample(m: &mut mem::Memory, len: u16, loop_len: u16, seed: u32) -> u16 {
+    let mut data = vec![0; 8];
+    BE::write_u16(&mut data[0..], len / 2);
+    BE::write_u16(&mut data[2..], loop_len / 2);
+
+    let mut x = seed | 1;
+    for _ in 0..u32::from(len & !1) + u32::from(loop_len & !1) {
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        data.push((x >> 24) as u8);
+    }
+    mem::add_entry(m, mem::entry_kind::SOUND, &data).expect("no space for sample")
+}
+
+// Samples with a loop play until stopped. Scene changes stop every channel
+// through `stop_sound_and_music`, so looped effects don't outlive a part.
+pub fn play_sound(g: &mut Game, channel: u8, res_num: u16, freq: u16, volume: u8) {
+    let data = match mem::entry_slice(&g.mem, res_num) {
+        Some(data) if data.len() >= 8 => data,
+        _ => return,
+    };
     let len = BE::read_u16(data) * 2;
     let loop_len = BE::read_u16(&data[2..]) * 2;
 
@@ -285,27 +482,34 @@ pub fn play_sound(g: &mut Game, channel: u8, address: usize, freq: u16, volume:
     } else {
         (len, 0)
     };
+    if 8 + usize::from(len) > data.len() {
+        log::warn!("sound {} is truncated", res_num);
+        return;
+    }
 
-    crate::host::play_sound(
-        &mut g.host,
-        channel,
-        freq,
-        volume,
-        &data[8..],
-        len.into(),
-        loops,
-    );
+    g.host
+        .play_sound(channel, freq, volume, &data[8..], len.into(), loops);
 }
 
 pub fn stop_sound(g: &mut Game, channel: u8) {
-    crate::host::stop_sound(&mut g.host, channel);
+    g.host.stop_sound(channel);
 }
 
-pub fn stop_sound_and_music(g: &mut Game) {
-    for channel in 0..4 {
+// Fades out sound on given channel, which also ends looped samples.
+pub fn fade_out_sound(g: &mut Game, channel: u8, ms: u16) {
+    g.host.fade_out_sound(channel, ms.into());
+}
+
+// Stops all sound effects, leaving music playing.
+pub fn reset_sfx_channels(g: &mut Game) {
+    for channel in 0..g.host.sound_channel_count() {
         stop_sound(g, channel);
     }
-    g.music.set_delay(0);
+}
+
+pub fn stop_sound_and_music(g: &mut Game) {
+    reset_sfx_channels(g);
+    stop_music(g);
 }
 
 #[derive(Default, Clone, Copy)]
@@ -346,3 +550,348 @@ impl Frac {
             >> Frac::BITS) as i16
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    // Music resource with a single pattern of given `(note1, note2)` rows
+    // on channel 0, at 20 ms per row. Instrument 1 is `instrument`.
+    fn music_resource(instrument: u16, rows: &[(u16, u16)]) -> Vec<u8> {
+        let mut data = vec![0; 0xC0 + 1024];
+        BE::write_u16(&mut data[0..], 2350);
+        BE::write_u16(&mut data[2..], instrument);
+        BE::write_u16(&mut data[4..], 0x3F);
+        BE::write_u16(&mut data[0x3E..], 1);
+        for (i, &(note1, note2)) in rows.iter().enumerate() {
+            BE::write_u16(&mut data[0xC0 + i * 16..], note1);
+            BE::write_u16(&mut data[0xC2 + i * 16..], note2);
+        }
+        data
+    }
+
+    #[test]
+    fn synth_sample_writes_header_and_noise() {
+        let mut m = testing::memory("synth_sample_writes_header_and_noise", &[]);
+        let res = synth_sample(&mut m, 100, 20, 1);
+        let data = mem::entry_slice(&m, res).unwrap().to_vec();
+        assert_eq!(mem::kind_of_entry(&m, res), Some(mem::entry_kind::SOUND));
+        assert_eq!(data.len(), 8 + 100 + 20);
+        assert_eq!(BE::read_u16(&data[0..]), 50);
+        assert_eq!(BE::read_u16(&data[2..]), 10);
+        assert!(data[8..].iter().any(|&b| b != 0));
+
+        let again = synth_sample(&mut m, 100, 20, 1);
+        assert_ne!(again, res);
+        assert_eq!(mem::entry_slice(&m, again).unwrap(), &data[..]);
+    }
+
+    #[test]
+    fn music_mixes_synthesized_instrument() {
+        let mut g = testing::game("music_mixes_synthesized_instrument", &[0x06]);
+        let sample = synth_sample(&mut g.mem, 2000, 0, 7);
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(sample, &[(0x1AC, 0x1000)]),
+        )
+        .unwrap();
+
+        seek(&mut g, music, 0, 0);
+        assert_eq!(g.music.current_delay(), 20);
+        let out = render(&mut g, 1000);
+        assert!(out.chunks_exact(2).any(|pair| pair[0] != 0));
+        assert!(out.chunks_exact(2).all(|pair| pair[1] == 0));
+    }
+
+    #[test]
+    fn out_of_range_note_leaves_channel_playing() {
+        let mut g = testing::game("out_of_range_note_leaves_channel_playing", &[0x06]);
+        let sample = synth_sample(&mut g.mem, 8000, 0, 3);
+        let rows = [(0x1AC, 0x1000), (0x0010, 0x1520)];
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(sample, &rows),
+        )
+        .unwrap();
+
+        seek(&mut g, music, 0, 0);
+        render(&mut g, 882 * 3);
+
+        assert_eq!(g.music.current_pos(), 3 * 16);
+        let status = g.music.channel_status()[0];
+        assert!(status.active);
+        assert_eq!(status.volume, 0x3F);
+    }
+
+    #[test]
+    fn reset_sfx_channels_keeps_music() {
+        let host = testing::TestHost::default();
+        let sounds = host.sounds.clone();
+        let mut g = testing::game_with_host("reset_sfx_channels_keeps_music", &[0x06], host);
+        let sample = synth_sample(&mut g.mem, 8000, 0, 3);
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(sample, &[(0x1AC, 0x1000)]),
+        )
+        .unwrap();
+
+        play_music(&mut g, music, 0, 0);
+        play_sound(&mut g, 1, sample, 8000, 0x3F);
+        play_sound(&mut g, 3, sample, 8000, 0x3F);
+        assert_eq!(sounds.get(), 0b1010);
+
+        reset_sfx_channels(&mut g);
+        assert_eq!(sounds.get(), 0);
+        assert_eq!(g.music.current_delay(), 20);
+        render(&mut g, 882);
+        assert!(g.music.channel_status()[0].active);
+    }
+
+    #[test]
+    fn missing_instrument_is_skipped() {
+        let mut g = testing::game("missing_instrument_is_skipped", &[0x06]);
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(0x7F, &[(0x1AC, 0x1000)]),
+        )
+        .unwrap();
+
+        play_music(&mut g, music, 0, 0);
+        assert_eq!(g.music.current_delay(), 20);
+        let out = render(&mut g, 882 * 3);
+        assert!(out.iter().all(|&s| s == 0));
+        assert!(!g.music.channel_status()[0].active);
+        assert_eq!(g.music.current_pos(), 3 * 16);
+    }
+
+    #[test]
+    fn play_and_stop_music() {
+        let mut g = testing::game("play_and_stop_music", &[0x06]);
+        let sample = synth_sample(&mut g.mem, 2000, 0, 5);
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(sample, &[(0x1AC, 0x1000)]),
+        )
+        .unwrap();
+        assert!(g.music.is_end_of_track());
+
+        play_music(&mut g, music, 0, 0x80);
+        assert!(g.music.is_end_of_track());
+
+        play_music(&mut g, music, 0, 0);
+        assert!(!g.music.is_end_of_track());
+        assert!(render(&mut g, 1000).iter().any(|&s| s != 0));
+
+        stop_music(&mut g);
+        assert!(g.music.is_end_of_track());
+        assert!(render(&mut g, 1000).iter().all(|&s| s == 0));
+    }
+
+    #[test]
+    fn nr_filters_channels_separately() {
+        // Impulses on the left in the first frame, on the right in the
+        // second, and on both in the last one, carried over to next buffer.
+        let mut out = vec![0i16; 2 * 64];
+        out[0] = 1000;
+        out[3] = -1000;
+        out[126] = 800;
+        out[127] = 600;
+        let mut prev = [0; 2];
+        nr(&mut prev, &mut out);
+
+        let (left, right): (Vec<i16>, Vec<i16>) =
+            out.chunks_exact(2).map(|pair| (pair[0], pair[1])).unzip();
+        let mut expected_left = vec![0; 64];
+        expected_left[..2].copy_from_slice(&[500, 500]);
+        expected_left[63] = 400;
+        let mut expected_right = vec![0; 64];
+        expected_right[1..3].copy_from_slice(&[-500, -500]);
+        expected_right[63] = 300;
+        assert_eq!(left, expected_left);
+        assert_eq!(right, expected_right);
+
+        let mut next = vec![0i16; 4];
+        nr(&mut prev, &mut next);
+        assert_eq!(next, [400, 300, 0, 0]);
+    }
+
+    #[test]
+    fn seek_reads_order_count_from_header() {
+        let mut g = testing::game("seek_reads_order_count_from_header", &[0x06]);
+        let mut resource = music_resource(0, &[]);
+        BE::write_u16(&mut resource[0x3E..], 3);
+        resource[0x40..0x43].copy_from_slice(&[2, 0, 1]);
+        // Data before the resource, so an offset applied twice reads
+        // elsewhere.
+        mem::add_entry(&mut g.mem, mem::entry_kind::SOUND, &[0xAA; 0x100]).unwrap();
+        let music = mem::add_entry(&mut g.mem, mem::entry_kind::MUSIC, &resource).unwrap();
+
+        seek(&mut g, music, 0, 1);
+        assert_eq!(g.music.track.num_order, 3);
+        assert_eq!(g.music.order_table(), &[2, 0, 1]);
+        assert_eq!(g.music.current_order(), 1);
+    }
+
+    #[test]
+    fn order_accessors_follow_playback() {
+        let mut g = testing::game("order_accessors_follow_playback", &[0x06]);
+        let mut resource = music_resource(0, &[]);
+        BE::write_u16(&mut resource[0x3E..], 2);
+        resource[0x40..0x42].copy_from_slice(&[0, 0]);
+        let music = mem::add_entry(&mut g.mem, mem::entry_kind::MUSIC, &resource).unwrap();
+
+        play_music(&mut g, music, 0, 0);
+        assert_eq!(g.music.order_table(), &[0, 0]);
+        assert_eq!(g.music.current_order(), 0);
+        assert_eq!(g.music.current_pos(), 0);
+
+        // Pattern has 64 rows of 16 bytes.
+        render(&mut g, 882 * 64);
+        assert_eq!(g.music.current_order(), 1);
+        assert_eq!(g.music.current_pos(), 0);
+    }
+
+    #[test]
+    fn paused_music_resumes_where_it_stopped() {
+        let mut g = testing::game("paused_music_resumes_where_it_stopped", &[0x06]);
+        let rows = [(0xFFFD, 1), (0xFFFD, 2), (0xFFFD, 3)];
+        let music = mem::add_entry(
+            &mut g.mem,
+            mem::entry_kind::MUSIC,
+            &music_resource(0, &rows),
+        )
+        .unwrap();
+
+        play_music(&mut g, music, 0, 0);
+        render(&mut g, 1);
+        assert_eq!(g.vm.music_sync(), 1);
+
+        set_music_paused(&mut g, true);
+        let out = render(&mut g, 882 * 2);
+        assert!(out.iter().all(|&s| s == 0));
+        assert_eq!(g.vm.music_sync(), 1);
+        assert_eq!((g.music.current_order(), g.music.current_pos()), (0, 16));
+
+        set_music_paused(&mut g, false);
+        render(&mut g, 881);
+        assert_eq!(g.vm.music_sync(), 1);
+        render(&mut g, 1);
+        assert_eq!(g.vm.music_sync(), 2);
+        assert_eq!((g.music.current_order(), g.music.current_pos()), (0, 32));
+    }
+
+    #[test]
+    fn amiga_audio_changes_rendered_note() {
+        let mut outs = Vec::new();
+        for &amiga in &[false, true] {
+            let name = format!("amiga_audio_changes_rendered_note_{}", amiga);
+            let mut g = testing::game(&name, &[0x06]);
+            let sample = synth_sample(&mut g.mem, 8000, 0, 5);
+            let music = mem::add_entry(
+                &mut g.mem,
+                mem::entry_kind::MUSIC,
+                &music_resource(sample, &[(0x1AC, 0x1000)]),
+            )
+            .unwrap();
+            g.music.set_amiga_audio(amiga);
+
+            play_music(&mut g, music, 0, 0);
+            let out = render(&mut g, 882);
+            assert!(out.iter().any(|&s| s != 0));
+            outs.push(out);
+        }
+        // Same note, but held instead of interpolated samples and filtered.
+        assert_ne!(outs[0], outs[1]);
+    }
+
+    #[test]
+    fn order_past_track_length_is_rejected() {
+        let mut g = testing::game("order_past_track_length_is_rejected", &[0x06]);
+        let mut resource = music_resource(0, &[]);
+        BE::write_u16(&mut resource[0x3E..], 3);
+        resource[0x40..0x43].copy_from_slice(&[2, 0, 1]);
+        let music = mem::add_entry(&mut g.mem, mem::entry_kind::MUSIC, &resource).unwrap();
+
+        play_music(&mut g, music, 0, 2);
+        render(&mut g, 882);
+        let state = (
+            g.music.current_order(),
+            g.music.current_pos(),
+            g.music.current_delay(),
+        );
+        assert_eq!(state, (2, 16, 20));
+
+        play_music(&mut g, music, 0, 3);
+        play_music(&mut g, music, 0, 0x7F);
+        assert_eq!(
+            (
+                g.music.current_order(),
+                g.music.current_pos(),
+                g.music.current_delay(),
+            ),
+            state
+        );
+    }
+
+    #[test]
+    fn render_advances_by_exact_frames() {
+        let rows = [(0x1AC, 0x1000), (0x1AC, 0x1000), (0x1AC, 0x1000)];
+        let mut games = Vec::new();
+        for name in &[
+            "render_advances_by_exact_frames",
+            "render_advances_by_exact_frames_split",
+        ] {
+            let mut g = testing::game(name, &[0x06]);
+            let sample = synth_sample(&mut g.mem, 4000, 0, 9);
+            let music = mem::add_entry(
+                &mut g.mem,
+                mem::entry_kind::MUSIC,
+                &music_resource(sample, &rows),
+            )
+            .unwrap();
+            seek(&mut g, music, 0, 0);
+            games.push(g);
+        }
+
+        // 882 frames per 20 ms row at 44.1 kHz.
+        let whole = render(&mut games[0], 882 * 2);
+        assert_eq!(whole.len(), 2 * 882 * 2);
+        assert_eq!(games[0].music.current_pos(), 2 * 16);
+
+        let mut split = render(&mut games[1], 1000);
+        split.extend(render(&mut games[1], 882 * 2 - 1000));
+        assert_eq!(split, whole);
+        assert_eq!(games[1].music.current_pos(), 2 * 16);
+    }
+
+    #[test]
+    fn stereo_width_blends_channels() {
+        let mut outputs = Vec::new();
+        for &width in &[0.0, 1.0] {
+            let mut g = testing::game("stereo_width_blends_channels", &[0x06]);
+            let sample = synth_sample(&mut g.mem, 2000, 0, 11);
+            let music = mem::add_entry(
+                &mut g.mem,
+                mem::entry_kind::MUSIC,
+                &music_resource(sample, &[(0x1AC, 0x1000)]),
+            )
+            .unwrap();
+            g.music.set_stereo_width(width);
+            seek(&mut g, music, 0, 0);
+            outputs.push(render(&mut g, 1000));
+        }
+
+        // Channel 0 is panned left.
+        let (mono, full) = (&outputs[0], &outputs[1]);
+        assert!(mono.iter().any(|&s| s != 0));
+        assert!(mono.chunks_exact(2).all(|pair| pair[0] == pair[1]));
+        assert!(full.chunks_exact(2).any(|pair| pair[0] != 0));
+        assert!(full.chunks_exact(2).all(|pair| pair[1] == 0));
+    }
+}
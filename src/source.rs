@@ -0,0 +1,124 @@
+// Game data can be read from loose files (`memlist.bin` and `bankXX`) or
+// from a package holding the same files.
+
+use crate::pak::Package;
+use std::io::{self, Read, Seek};
+use std::path::{Path, PathBuf};
+
+const MEMLIST_NAME: &str = "memlist.bin";
+const PAK_NAME: &str = "Pak01.pak";
+
+pub enum Source {
+    Dir(PathBuf),
+    Pak(Package),
+}
+
+impl Source {
+    // Loose files are preferred when both are present.
+    pub fn detect(dir: &Path) -> Result<Self, String> {
+        if dir.join(MEMLIST_NAME).is_file() {
+            log::info!("using data files from {}", dir.display());
+            return Ok(Source::Dir(dir.into()));
+        }
+
+        let pak_path = dir.join(PAK_NAME);
+        if pak_path.is_file() {
+            let pak = Package::open(&pak_path)
+                .map_err(|e| format!("unable to open {}: {}", pak_path.display(), e))?;
+            log::info!("using data package {}", pak_path.display());
+            return Ok(Source::Pak(pak));
+        }
+
+        Err(format!(
+            "no game data found in {}, looked for `{}` or `{}`",
+            dir.display(),
+            MEMLIST_NAME,
+            PAK_NAME
+        ))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        match self {
+            Source::Dir(dir) => dir.join(name).is_file(),
+            Source::Pak(pak) => pak.find(name).is_some(),
+        }
+    }
+
+    pub fn read_all(&self, name: &str) -> io::Result<Vec<u8>> {
+        match self {
+            Source::Dir(dir) => std::fs::read(dir.join(name)),
+            Source::Pak(pak) => Ok(pak.load(find_entry(pak, name)?)?),
+        }
+    }
+
+    pub fn read_at(&self, name: &str, pos: u64, dst: &mut [u8]) -> io::Result<()> {
+        match self {
+            Source::Dir(dir) => {
+                let mut f = std::fs::File::open(dir.join(name))?;
+                f.seek(io::SeekFrom::Start(pos))?;
+                f.read_exact(dst)
+            }
+            Source::Pak(pak) => {
+                let data = pak.load(find_entry(pak, name)?)?;
+                let begin = pos as usize;
+                match data.get(begin..begin + dst.len()) {
+                    Some(src) => {
+                        dst.copy_from_slice(src);
+                        Ok(())
+                    }
+                    None => Err(io::ErrorKind::UnexpectedEof.into()),
+                }
+            }
+        }
+    }
+}
+
+fn find_entry<'a>(pak: &'a Package, name: &str) -> io::Result<&'a crate::pak::Entry> {
+    pak.find(name).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("`{}` not found in package", name),
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, write_pak};
+
+    #[test]
+    fn dir_with_only_package_uses_package() {
+        let dir = testing::temp_dir("dir_with_only_package_uses_package");
+        write_pak(
+            &dir.join(PAK_NAME),
+            &[(MEMLIST_NAME, b"list"), ("bank01", b"bank")],
+        );
+
+        let source = Source::detect(&dir).unwrap();
+        assert!(matches!(source, Source::Pak(_)));
+        assert!(source.contains("BANK01"));
+        assert_eq!(source.read_all(MEMLIST_NAME).unwrap(), b"list");
+        let mut buf = [0; 2];
+        source.read_at("bank01", 1, &mut buf).unwrap();
+        assert_eq!(&buf, b"an");
+    }
+
+    #[test]
+    fn loose_files_are_preferred() {
+        let dir = testing::temp_dir("loose_files_are_preferred");
+        write_pak(&dir.join(PAK_NAME), &[(MEMLIST_NAME, b"list")]);
+        std::fs::write(dir.join(MEMLIST_NAME), b"loose").unwrap();
+
+        let source = Source::detect(&dir).unwrap();
+        assert!(matches!(source, Source::Dir(_)));
+        assert_eq!(source.read_all(MEMLIST_NAME).unwrap(), b"loose");
+    }
+
+    #[test]
+    fn missing_data_lists_expected_files() {
+        let dir = testing::temp_dir("missing_data_lists_expected_files");
+        let e = Source::detect(&dir).err().unwrap();
+        assert!(e.contains(MEMLIST_NAME) && e.contains(PAK_NAME), "{}", e);
+    }
+}
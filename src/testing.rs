@@ -0,0 +1,398 @@
+// Game data, games and a host for tests, built without the original data
+// files.
+//
+// Data sets have a `memlist.bin` with at least the 0x80 entries referenced
+// by parts, all stored in `bank01`. Entries not given are empty.
+
+use crate::host::Action;
+use crate::mem::{self, entry_kind};
+use crate::source::Source;
+use crate::video::soft;
+use crate::{script, Game, HostBackend, Memory, NullHost, INTRO_PART};
+use byteorder::{ByteOrder, BE, LE};
+use std::cell::Cell;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+// Resources of the introduction part, see `mem::MEM_LIST_PARTS`.
+pub const INTRO_PALETTE: u16 = 0x17;
+pub const INTRO_CODE: u16 = 0x18;
+pub const INTRO_SHAPES: u16 = 0x19;
+
+// Empty directory unique to given test.
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("oorw-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+// Writes a data set with `(index, kind, contents)` resources to `dir`.
+pub fn write_data_set(dir: &Path, resources: &[(u16, u8, &[u8])]) {
+    write_bank(dir, resources, false);
+}
+
+// Like `write_data_set`, but resources are packed when that makes them
+// smaller.
+pub fn write_packed_data_set(dir: &Path, resources: &[(u16, u8, &[u8])]) {
+    write_bank(dir, resources, true);
+}
+
+fn write_bank(dir: &Path, resources: &[(u16, u8, &[u8])], pack: bool) {
+    let count = resources
+        .iter()
+        .map(|&(index, _, _)| usize::from(index) + 1)
+        .max()
+        .unwrap_or(0)
+        .max(0x80);
+
+    let mut memlist = vec![0; count * 20];
+    let mut bank = Vec::new();
+    for (i, entry) in memlist.chunks_exact_mut(20).enumerate() {
+        let (kind, contents) = resources
+            .iter()
+            .find(|&&(index, _, _)| usize::from(index) == i)
+            .map_or((entry_kind::BANK, &[][..]), |&(_, kind, contents)| {
+                (kind, contents)
+            });
+        let packed = Some(contents).filter(|_| pack).and_then(self::pack);
+        let stored = packed.as_deref().unwrap_or(contents);
+        entry[1] = kind;
+        entry[7] = 1;
+        BE::write_u32(&mut entry[8..], bank.len() as u32);
+        BE::write_u32(&mut entry[12..], stored.len() as u32);
+        BE::write_u32(&mut entry[16..], contents.len() as u32);
+        bank.extend_from_slice(stored);
+    }
+    memlist.push(0xFF);
+    memlist.resize(memlist.len() + 19, 0);
+
+    std::fs::write(dir.join("memlist.bin"), memlist).unwrap();
+    std::fs::write(dir.join("bank01"), bank).unwrap();
+}
+
+// Writes a package with given plain entries, directory at the end.
+pub fn write_pak(path: &Path, entries: &[(&str, &[u8])]) {
+    let mut data = vec![0; 12];
+    let mut dir = Vec::new();
+    for (name, contents) in entries {
+        let mut entry = [0; 0x40];
+        entry[..name.len()].copy_from_slice(name.as_bytes());
+        LE::write_u32(&mut entry[0x38..], data.len() as u32);
+        LE::write_u32(&mut entry[0x3C..], contents.len() as u32);
+        dir.extend_from_slice(&entry);
+        data.extend_from_slice(contents);
+    }
+    let dir_offset = data.len() as u32;
+    data[0..4].copy_from_slice(b"PACK");
+    LE::write_u32(&mut data[4..], dir_offset);
+    LE::write_u32(&mut data[8..], dir.len() as u32);
+    data.extend_from_slice(&dir);
+    std::fs::write(path, data).unwrap();
+}
+
+pub fn memory(name: &str, resources: &[(u16, u8, &[u8])]) -> Memory {
+    let dir = temp_dir(name);
+    write_data_set(&dir, resources);
+    Memory::new(Source::detect(&dir).unwrap(), mem::DATA_SIZE).unwrap()
+}
+
+// Game running the introduction part with given bytecode, on a `NullHost`.
+pub fn game(name: &str, code: &[u8]) -> Game {
+    game_with_host(name, code, NullHost::default())
+}
+
+pub fn game_with_host(name: &str, code: &[u8], host: impl HostBackend + 'static) -> Game {
+    let palette = [0; 2048];
+    let shapes = [0; 16];
+    let mem = memory(
+        name,
+        &[
+            (INTRO_PALETTE, entry_kind::PALETTE, &palette),
+            (INTRO_CODE, entry_kind::BYTECODE, code),
+            (INTRO_SHAPES, entry_kind::SHAPE, &shapes),
+        ],
+    );
+    start(mem, host)
+}
+
+// Game running the introduction part from given memory, on a `NullHost`.
+pub fn game_with_memory(mem: Memory) -> Game {
+    start(mem, NullHost::default())
+}
+
+fn start(mem: Memory, host: impl HostBackend + 'static) -> Game {
+    let mut g = Game::new(Box::new(host), mem);
+    crate::restart_at(&mut g, INTRO_PART, -1);
+    g
+}
+
+// Host whose idle time, pause and refresh interval are set by the test.
+// `sounds` has a bit set for every channel playing a sound.
+#[derive(Default)]
+pub struct TestHost {
+    inner: NullHost,
+    pub idle: Rc<Cell<Duration>>,
+    pub paused: Rc<Cell<bool>>,
+    pub refresh_interval: Rc<Cell<Option<Duration>>>,
+    pub sounds: Rc<Cell<u8>>,
+}
+
+impl HostBackend for TestHost {
+    fn display_surface(&mut self, rndr: &soft::State, fb: u8, skipped: &[(i16, i16)]) {
+        self.inner.display_surface(rndr, fb, skipped)
+    }
+
+    fn sound_channel_count(&self) -> u8 {
+        self.inner.sound_channel_count()
+    }
+
+    fn play_sound(
+        &mut self,
+        channel: u8,
+        freq: u16,
+        volume: u8,
+        data: &[u8],
+        len: usize,
+        loops: i32,
+    ) {
+        self.sounds.set(self.sounds.get() | 1 << channel);
+        self.inner
+            .play_sound(channel, freq, volume, data, len, loops)
+    }
+
+    fn stop_sound(&mut self, channel: u8) {
+        self.sounds.set(self.sounds.get() & !(1 << channel));
+        self.inner.stop_sound(channel)
+    }
+
+    fn fade_out_sound(&mut self, channel: u8, ms: i32) {
+        self.inner.fade_out_sound(channel, ms)
+    }
+
+    fn music_space(&self) -> Option<usize> {
+        self.inner.music_space()
+    }
+
+    fn queue_music(&mut self, samples: &mut [i16]) {
+        self.inner.queue_music(samples)
+    }
+
+    fn process_input(&mut self, input: &mut script::Input, photo_mode: bool) -> Vec<Action> {
+        self.inner.process_input(input, photo_mode)
+    }
+
+    fn wants_quit(&self) -> bool {
+        self.inner.wants_quit()
+    }
+
+    fn request_quit(&mut self) {
+        self.inner.request_quit()
+    }
+
+    fn wants_pause(&self) -> bool {
+        self.paused.get()
+    }
+
+    fn idle_time(&self) -> Duration {
+        self.idle.get()
+    }
+
+    fn reset_idle_time(&mut self) {
+        self.idle.set(Duration::from_secs(0));
+    }
+
+    fn refresh_interval(&self) -> Option<Duration> {
+        self.refresh_interval.get()
+    }
+}
+
+// Longest distance and length of a copy `pack` emits.
+const MAX_OFFSET: usize = 4095;
+const MAX_COPY: usize = 256;
+// Candidates checked for each position, trading ratio for speed.
+const MAX_CHAIN: usize = 256;
+
+struct BitWriter {
+    bits: Vec<bool>,
+}
+
+impl BitWriter {
+    // Writes low `count` bits of `value`, MSB first as `bytekiller::unpack` reads them.
+    fn put(&mut self, value: usize, count: u32) {
+        for i in (0..count).rev() {
+            self.bits.push((value >> i) & 1 != 0);
+        }
+    }
+}
+
+// Position in the bit stream and in the output after each command, for
+// checking that unpacking in place doesn't overwrite unread data.
+struct Progress {
+    bits: usize,
+    unpacked: usize,
+}
+
+// Packs `data` so that `bytekiller::unpack` restores it in place. Data is packed
+// backwards, as it's unpacked from the end. Returns `None` when that doesn't
+// make it smaller, in which case resources are stored as is.
+pub fn pack(data: &[u8]) -> Option<Vec<u8>> {
+    if data.is_empty() {
+        return None;
+    }
+    let rev: Vec<u8> = data.iter().rev().copied().collect();
+    let mut w = BitWriter { bits: Vec::new() };
+    let mut progress = Vec::new();
+    let mut literals = 0;
+
+    // Most recent position with given two bytes and the previous one for
+    // each position.
+    let mut head = vec![usize::MAX; 1 << 16];
+    let mut prev = vec![usize::MAX; rev.len()];
+    let key = |pos: usize| usize::from(rev[pos]) << 8 | usize::from(rev[pos + 1]);
+
+    let mut pos = 0;
+    while pos < rev.len() {
+        let (len, offset) = find_match(&rev, pos, &head, &prev);
+        let step = if len == 0 { 1 } else { len };
+        for p in pos..(pos + step).min(rev.len() - 1) {
+            prev[p] = head[key(p)];
+            head[key(p)] = p;
+        }
+        if len == 0 {
+            literals += 1;
+            pos += 1;
+            continue;
+        }
+        write_literals(&mut w, &mut progress, &rev, pos, literals);
+        literals = 0;
+        match (len, offset) {
+            (2, _) => {
+                w.put(0b01, 2);
+                w.put(offset, 8);
+            }
+            (3, 0..=511) => {
+                w.put(0b100, 3);
+                w.put(offset, 9);
+            }
+            (4, 0..=1023) => {
+                w.put(0b101, 3);
+                w.put(offset, 10);
+            }
+            _ => {
+                w.put(0b110, 3);
+                w.put(len - 1, 8);
+                w.put(offset, 12);
+            }
+        }
+        pos += len;
+        progress.push(Progress {
+            bits: w.bits.len(),
+            unpacked: pos,
+        });
+    }
+    write_literals(&mut w, &mut progress, &rev, pos, literals);
+
+    // Bits left over from whole words go to the first word read, below its
+    // end marker bit.
+    let bits = &w.bits;
+    let first_len = bits.len() % 32;
+    let words = bits.len() / 32;
+    let packed_len = (words + 3) * 4;
+    if packed_len >= data.len() {
+        return None;
+    }
+    // Word `j` is read when reaching its first bit, it must not be written
+    // over before then.
+    let mut unpacked = 0;
+    let mut next = progress.iter();
+    for j in 1..=words {
+        let first_bit = first_len + 32 * (j - 1);
+        for p in next.by_ref() {
+            unpacked = p.unpacked;
+            if p.bits > first_bit {
+                break;
+            }
+        }
+        let end = packed_len - 12 - 4 * j + 4;
+        if end > data.len() - unpacked {
+            return None;
+        }
+    }
+
+    let word = |bits: &[bool]| {
+        bits.iter()
+            .enumerate()
+            .fold(0u32, |word, (i, &bit)| word | u32::from(bit) << i)
+    };
+    let mut out = vec![0; packed_len];
+    let first = word(&bits[..first_len]) | 1 << first_len;
+    let mut crc = first;
+    for (j, chunk) in bits[first_len..].chunks(32).enumerate() {
+        let value = word(chunk);
+        crc ^= value;
+        BE::write_u32(&mut out[packed_len - 16 - 4 * j..], value);
+    }
+    BE::write_u32(&mut out[packed_len - 12..], first);
+    BE::write_u32(&mut out[packed_len - 8..], crc);
+    BE::write_u32(&mut out[packed_len - 4..], data.len() as u32);
+    Some(out)
+}
+
+// Longest earlier copy of the data at `pos` which has a command, as length
+// and offset back, or length 0 when there's none.
+fn find_match(rev: &[u8], pos: usize, head: &[usize], prev: &[usize]) -> (usize, usize) {
+    if pos + 2 > rev.len() {
+        return (0, 0);
+    }
+    let limit = MAX_COPY.min(rev.len() - pos);
+    let mut best = (0, 0);
+    let mut candidate = head[usize::from(rev[pos]) << 8 | usize::from(rev[pos + 1])];
+    for _ in 0..MAX_CHAIN {
+        if candidate == usize::MAX || pos - candidate > MAX_OFFSET {
+            break;
+        }
+        let offset = pos - candidate;
+        let len = (0..limit)
+            .take_while(|&i| rev[candidate + i] == rev[pos + i])
+            .count();
+        if len > best.0 && (len > 2 || offset <= 255) {
+            best = (len, offset);
+            if len == limit {
+                break;
+            }
+        }
+        candidate = prev[candidate];
+    }
+    best
+}
+
+// Writes `count` bytes preceding `end` as literals.
+fn write_literals(
+    w: &mut BitWriter,
+    progress: &mut Vec<Progress>,
+    rev: &[u8],
+    end: usize,
+    count: usize,
+) {
+    let mut pos = end - count;
+    while pos < end {
+        let n = (end - pos).min(264);
+        if n <= 8 {
+            w.put(0b00, 2);
+            w.put(n - 1, 3);
+        } else {
+            w.put(0b111, 3);
+            w.put(n - 9, 8);
+        }
+        for &b in &rev[pos..pos + n] {
+            w.put(b.into(), 8);
+        }
+        pos += n;
+        progress.push(Progress {
+            bits: w.bits.len(),
+            unpacked: pos,
+        });
+    }
+}
@@ -1,9 +1,15 @@
 use super::data;
+use super::png;
 use super::Game;
 use byteorder::{ByteOrder, BE};
+use soft::{FB_SIZE, SCR_H, SCR_W};
+use std::borrow::Cow;
 use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 
+pub mod shape;
 pub mod soft;
+pub mod vectors;
 
 pub struct VideoContext {
     pub rndr: soft::State,
@@ -13,10 +19,28 @@ pub struct VideoContext {
     use_seg2: bool,
     // This can only be true for DOS data-set.
     use_ega_pal: bool,
+    pal_expansion: PalExpansion,
     current_pal_num: Option<u8>,
+    // Debug override for palette requested by scripts.
+    forced_pal_num: Option<u8>,
     needs_pal_fixup: bool,
+    bitmap_dump_dir: Option<PathBuf>,
+    // Number of polygons drawn since last `take_polygon_count`.
+    polygon_count: u32,
+    // Debug mode where everything is drawn to and displayed from page 0.
+    no_flip: bool,
+    text_transform: Option<TextTransform>,
+    // Positions of shapes skipped due to bad data since last present, only
+    // collected in strict mode.
+    strict: bool,
+    skipped_draws: Vec<(i16, i16)>,
+    vectors: Option<vectors::Recorder>,
 }
 
+// Called by `draw_string` with string ID and text to draw. The returned text
+// is laid out the same way, including line breaks.
+pub type TextTransform = Box<dyn for<'a> Fn(u16, &'a str) -> Cow<'a, str>>;
+
 pub struct QuadStrip {
     vertices: [Vertex; 70],
     count: usize,
@@ -35,6 +59,12 @@ pub struct RgbColor {
     pub b: u8,
 }
 
+impl Default for QuadStrip {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl QuadStrip {
     pub fn new() -> Self {
         Self {
@@ -61,26 +91,36 @@ pub fn select_page(v: &mut VideoContext, n: u8) {
 
 pub fn fill_page(v: &mut VideoContext, n: u8, color: u8) {
     let n = translate_page(v, n);
+    v.record(|| vectors::Op::Fill { page: n, color });
     soft::clear_fb(&mut v.rndr, n, color)
 }
 
 pub fn copy_page(v: &mut VideoContext, src: u8, dst: u8, v_scroll: i16) {
+    if v.no_flip {
+        return;
+    }
+
     let dst = translate_page(v, dst);
-    if src >= 0xFE {
-        let src = translate_page(v, src);
-        soft::copy_fb(&mut v.rndr, dst, src, 0);
+    let (src, v_scroll) = if src >= 0xFE {
+        (translate_page(v, src), 0)
     } else if (src & 0x80) == 0 {
-        let src = translate_page(v, src & 0xBF);
-        soft::copy_fb(&mut v.rndr, dst, src, 0);
+        (translate_page(v, src & 0xBF), 0)
     } else {
         let src = translate_page(v, src & 3);
-        if src != dst && (-199..=199).contains(&v_scroll) {
-            soft::copy_fb(&mut v.rndr, dst, src, i32::from(v_scroll));
+        if src == dst {
+            return;
         }
-    }
+        (src, v_scroll)
+    };
+    v.record(|| vectors::Op::Copy { src, dst, v_scroll });
+    soft::copy_fb(&mut v.rndr, dst, src, i32::from(v_scroll));
 }
 
 pub fn swap_pages(v: &mut VideoContext, new_front_fb: u8) -> u8 {
+    if v.no_flip {
+        return 0;
+    }
+
     if new_front_fb != 0xFE {
         if new_front_fb == 0xFF {
             v.fb_xlat.swap(1, 2);
@@ -93,6 +133,10 @@ pub fn swap_pages(v: &mut VideoContext, new_front_fb: u8) -> u8 {
 }
 
 fn translate_page(v: &VideoContext, n: u8) -> u8 {
+    if v.no_flip {
+        return 0;
+    }
+
     match n {
         0..=3 => n,
         0xFE => v.fb_xlat[1],
@@ -109,19 +153,34 @@ pub fn draw_shape(g: &mut Game, x: i16, y: i16, zoom: u16, color: u8) {
     if i >= 0xC0 {
         let color = if (color & 0x80) != 0 { i & 0x3F } else { color };
 
-        let old_dc = g.video.dc;
-        fill_polygon(g, x, y, zoom, color);
-        g.video.dc = old_dc;
+        fill_polygon(&mut DcGuard::save(g), x, y, zoom, color);
     } else {
         let i = i & 0x3F;
         if i == 2 {
             draw_shape_parts(g, x, y, zoom);
         } else {
             log::warn!("invalid video op {}", i);
+            g.video.skip_draw(x, y);
         }
     }
 }
 
+// Decodes shape at given offset of the first or second video segment of
+// current part, scaled by `zoom` like `draw_shape`, see `shape` module.
+pub fn decode_shape(
+    g: &Game,
+    offset: u16,
+    use_seg2: bool,
+    zoom: u16,
+) -> Result<shape::Shape, String> {
+    let base = if use_seg2 {
+        g.mem.seg_video2()
+    } else {
+        g.mem.seg_video1()
+    };
+    shape::decode(&g.mem.data[base..], offset, zoom)
+}
+
 fn fill_polygon(g: &mut Game, x: i16, y: i16, zoom: u16, color: u8) {
     let bbw = fetch_dim(g, zoom);
     let bbh = fetch_dim(g, zoom);
@@ -140,6 +199,7 @@ fn fill_polygon(g: &mut Game, x: i16, y: i16, zoom: u16, color: u8) {
 
     if (num & 1) != 0 {
         log::warn!("unexpected number of vertices {}", num);
+        g.video.skip_draw(x, y);
         return;
     }
 
@@ -150,9 +210,21 @@ fn fill_polygon(g: &mut Game, x: i16, y: i16, zoom: u16, color: u8) {
     }
 
     let fb = g.video.fb_xlat[0];
+    g.video.polygon_count = g.video.polygon_count.wrapping_add(1);
     if num == 4 && bbw == 0 && bbh <= 1 {
+        g.video.record(|| vectors::Op::Point {
+            page: fb,
+            x,
+            y,
+            color,
+        });
         soft::draw_point(&mut g.video.rndr, fb, x as u16, y as u16, color);
     } else {
+        g.video.record(|| vectors::Op::Polygon {
+            page: fb,
+            color,
+            vertices: qs.vertices().to_vec(),
+        });
         soft::draw_polygon(&mut g.video.rndr, fb, &qs, color);
     }
 }
@@ -178,42 +250,261 @@ fn draw_shape_parts(g: &mut Game, x: i16, y: i16, zoom: u16) {
             0xFF
         };
 
-        let old_offset = std::mem::replace(&mut g.video.dc, offset << 1);
-        draw_shape(g, x, y, zoom, color);
-        g.video.dc = old_offset;
+        draw_shape(&mut DcGuard::set(g, offset << 1), x, y, zoom, color);
+    }
+}
+
+// Restores data counter and segment on drop, so shape drawing code can
+// return early without corrupting the caller's position.
+struct DcGuard<'a> {
+    g: &'a mut Game,
+    dc: u16,
+    use_seg2: bool,
+}
+
+impl<'a> DcGuard<'a> {
+    fn save(g: &'a mut Game) -> Self {
+        let (dc, use_seg2) = (g.video.dc, g.video.use_seg2);
+        Self { g, dc, use_seg2 }
+    }
+
+    fn set(g: &'a mut Game, dc: u16) -> Self {
+        let guard = Self::save(g);
+        guard.g.video.dc = dc;
+        guard
+    }
+}
+
+impl std::ops::Deref for DcGuard<'_> {
+    type Target = Game;
+
+    fn deref(&self) -> &Game {
+        self.g
+    }
+}
+
+impl std::ops::DerefMut for DcGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Game {
+        self.g
+    }
+}
+
+impl Drop for DcGuard<'_> {
+    fn drop(&mut self) {
+        self.g.video.dc = self.dc;
+        self.g.video.use_seg2 = self.use_seg2;
+    }
+}
+
+const GRID_COLOR: u8 = 0xF;
+
+// Draws coordinate grid with labels every 32 pixels over a copy of given
+// page and returns the page to display instead. The game pages are left
+// untouched.
+pub fn draw_grid(v: &mut VideoContext, fb: u8) -> u8 {
+    const STEP: usize = 32;
+
+    let page = *v.rndr.page(fb);
+    let dst = soft::OVERLAY_FB;
+    soft::draw_bitmap(&mut v.rndr, dst, &page);
+
+    for x in (0..SCR_W).step_by(STEP) {
+        for y in 0..SCR_H {
+            soft::draw_point(&mut v.rndr, dst, x, y, GRID_COLOR);
+        }
+        draw_label(v, dst, x + 2, 2, x);
+    }
+    for y in (0..SCR_H).step_by(STEP) {
+        for x in 0..SCR_W {
+            soft::draw_point(&mut v.rndr, dst, x, y, GRID_COLOR);
+        }
+        if y != 0 {
+            draw_label(v, dst, 2, y + 2, y);
+        }
+    }
+    dst
+}
+
+// Draws a line of text centered at the bottom of a copy of given page, on
+// a box of color 0, and returns the page to display instead. Text past the
+// screen width is cut.
+pub fn draw_caption(v: &mut VideoContext, fb: u8, text: &str) -> u8 {
+    const MAX_CHARS: usize = SCR_W as usize / 8;
+
+    let dst = soft::OVERLAY_FB;
+    if fb != dst {
+        let page = *v.rndr.page(fb);
+        soft::draw_bitmap(&mut v.rndr, dst, &page);
+    }
+
+    let len = std::cmp::min(text.chars().count(), MAX_CHARS) as u16;
+    let left = (SCR_W - len * 8) / 2;
+    let top = SCR_H - 12;
+    for y in top - 2..top + 10 {
+        for x in left.saturating_sub(2)..std::cmp::min(left + len * 8 + 2, SCR_W) {
+            soft::draw_point(&mut v.rndr, dst, x, y, 0);
+        }
+    }
+    for (i, c) in text.chars().take(MAX_CHARS).enumerate() {
+        soft::draw_char(&mut v.rndr, dst, left + (i as u16) * 8, top, c, GRID_COLOR);
+    }
+    dst
+}
+
+fn draw_label(v: &mut VideoContext, fb: u8, x: u16, y: u16, value: u16) {
+    for (i, c) in value.to_string().chars().enumerate() {
+        soft::draw_char(&mut v.rndr, fb, x + (i as u16) * 8, y, c, GRID_COLOR);
     }
 }
 
 pub fn draw_string(v: &mut VideoContext, mut xi: u16, mut ypos: u16, str_id: u16, color: u8) {
-    let text = if let Some(s) = find_string(data::STRINGS_EN, str_id) {
+    let text = if let Some(s) = lookup_string(str_id) {
         s
     } else {
         log::warn!("unknown string {}", str_id);
         return;
     };
+    let text = match &v.text_transform {
+        Some(transform) => transform(str_id, text),
+        None => Cow::Borrowed(text),
+    };
+    // Transformed text may have characters missing from the font.
+    let text: String = text
+        .chars()
+        .map(|c| match c {
+            '\n' | ' '..='~' => c,
+            _ => '?',
+        })
+        .collect();
+    // Only palette colors make sense for text, special values like
+    // `COL_ALPHA` aren't handled by `draw_char`.
+    let color = if color > 0xF {
+        log::warn!("invalid text color {}", color);
+        0xF
+    } else {
+        color
+    };
 
+    let page = v.fb_xlat[0];
+    v.record(|| vectors::Op::Text {
+        page,
+        x: xi.saturating_mul(8),
+        y: ypos,
+        color,
+        text: text.clone(),
+    });
     let left = xi;
     for c in text.chars() {
         if c == '\n' {
             xi = left;
-            ypos += 8;
+            ypos = ypos.saturating_add(8);
         } else {
-            let next_xi = xi + 1;
-            let xpos = std::mem::replace(&mut xi, next_xi) * 8;
+            let next_xi = xi.saturating_add(1);
+            let xpos = std::mem::replace(&mut xi, next_xi).saturating_mul(8);
             let fb = v.fb_xlat[0];
             soft::draw_char(&mut v.rndr, fb, xpos, ypos, c, color);
         }
     }
 }
 
+pub fn lookup_string(id: u16) -> Option<&'static str> {
+    find_string(data::STRINGS_EN, id)
+}
+
+// All known strings with their IDs, in table order.
+pub fn strings() -> impl Iterator<Item = (u16, &'static str)> {
+    data::STRINGS_EN.iter().copied()
+}
+
 fn find_string(table: &[(u16, &'static str)], id: u16) -> Option<&'static str> {
     table.iter().find(|item| item.0 == id).map(|item| item.1)
 }
 
+pub fn copy_bitmap(v: &mut VideoContext, mem: &[u8], res_num: usize) {
+    if mem.len() < BITMAP_SIZE {
+        log::warn!(
+            "bitmap {:02x} has {} bytes, expected {}",
+            res_num,
+            mem.len(),
+            BITMAP_SIZE
+        );
+        return;
+    }
+    let image = decode_bitmap(mem);
+
+    if let Some(dir) = &v.bitmap_dump_dir {
+        let path = dir.join(format!("bitmap{:02x}.png", res_num));
+        if let Err(e) = write_png(&path, &image, v.rndr.palette()) {
+            log::warn!("unable to write {}: {}", path.display(), e);
+        }
+    }
+
+    v.record(|| vectors::Op::Bitmap { page: 0, res_num });
+    soft::draw_bitmap(&mut v.rndr, 0, &image);
+}
+
+// Ends the frame of recorded vectors, with `fb` being displayed.
+pub fn record_frame(v: &mut VideoContext, fb: u8) {
+    if let Some(recorder) = &mut v.vectors {
+        if let Err(e) = recorder.end_frame(fb) {
+            log::warn!("unable to write vectors, stopping export: {}", e);
+            v.vectors = None;
+        }
+    }
+}
+
+// Writes page as displayed, in binary PPM format.
+pub fn dump_ppm(g: &Game, fb: u8, path: &Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    write!(f, "P6\n{} {}\n255\n", SCR_W, SCR_H)?;
+    let pal = g.video.rndr.display_palette();
+    for index in g.video.rndr.page(fb).iter() {
+        let color = pal[usize::from(*index)];
+        f.write_all(&[color.r, color.g, color.b])?;
+    }
+    f.flush()
+}
+
+// Writes the active palette as a GIMP palette when the path ends with
+// ".gpl", or as one RRGGBB hex color per line otherwise.
+pub fn write_palette(g: &Game, path: &Path) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let pal = g.video.rndr.palette();
+    if path.extension() == Some("gpl".as_ref()) {
+        writeln!(f, "GIMP Palette")?;
+        match g.video.current_pal_num {
+            Some(num) => writeln!(f, "Name: Part {} palette {}", g.current_part, num)?,
+            None => writeln!(f, "Name: Part {}", g.current_part)?,
+        }
+        writeln!(f, "Columns: 16\n#")?;
+        for (i, c) in pal.iter().enumerate() {
+            writeln!(f, "{:3} {:3} {:3}\tColor {}", c.r, c.g, c.b, i)?;
+        }
+    } else {
+        for c in pal.iter() {
+            writeln!(f, "{:02x}{:02x}{:02x}", c.r, c.g, c.b)?;
+        }
+    }
+    f.flush()
+}
+
+pub fn write_png(path: &Path, image: &[u8], pal: &[RgbColor]) -> std::io::Result<()> {
+    let mut f = std::io::BufWriter::new(std::fs::File::create(path)?);
+    png::write_indexed(&mut f, SCR_W.into(), SCR_H.into(), image, pal)
+}
+
+// Size of a bitmap resource, 4 bit-planes of 8000 bytes each.
+pub const BITMAP_SIZE: usize = 8000 * 4;
+
+// Deinterleaves 4 bit-planes of 8000 bytes each into an indexed image.
 #[allow(clippy::identity_op)]
 #[allow(clippy::erasing_op)]
-pub fn copy_bitmap(v: &mut VideoContext, mem: &[u8]) {
-    let mut image = [0; 320 * 200];
+pub fn decode_bitmap(mem: &[u8]) -> [u8; FB_SIZE] {
+    let mut image = [0; FB_SIZE];
     let mut di = 0;
 
     for y in 0..200 {
@@ -241,7 +532,13 @@ pub fn copy_bitmap(v: &mut VideoContext, mem: &[u8]) {
         }
     }
 
-    soft::draw_bitmap(&mut v.rndr, 0, &image);
+    image
+}
+
+impl Default for VideoContext {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VideoContext {
@@ -252,8 +549,17 @@ impl VideoContext {
             dc: 0,
             use_seg2: false,
             use_ega_pal: false,
+            pal_expansion: PalExpansion::Replicate,
             current_pal_num: None,
+            forced_pal_num: None,
             needs_pal_fixup: true,
+            bitmap_dump_dir: None,
+            polygon_count: 0,
+            no_flip: false,
+            text_transform: None,
+            strict: false,
+            skipped_draws: Vec::new(),
+            vectors: None,
         }
     }
 
@@ -273,6 +579,85 @@ impl VideoContext {
     pub fn set_use_ega_pal(&mut self, on: bool) {
         self.use_ega_pal = on;
     }
+
+    pub fn set_pal_expansion(&mut self, expansion: PalExpansion) {
+        self.pal_expansion = expansion;
+    }
+
+    pub fn set_forced_pal_num(&mut self, num: Option<u8>) {
+        self.forced_pal_num = num;
+        self.current_pal_num = None;
+    }
+
+    pub fn set_no_flip(&mut self, on: bool) {
+        self.no_flip = on;
+    }
+
+    pub fn set_strict(&mut self, on: bool) {
+        self.strict = on;
+    }
+
+    pub fn take_skipped_draws(&mut self) -> Vec<(i16, i16)> {
+        std::mem::take(&mut self.skipped_draws)
+    }
+
+    fn skip_draw(&mut self, x: i16, y: i16) {
+        if self.strict {
+            self.skipped_draws.push((x, y));
+        }
+    }
+
+    pub fn set_text_transform(&mut self, transform: Option<TextTransform>) {
+        self.text_transform = transform;
+    }
+
+    pub fn set_bitmap_dump_dir(&mut self, dir: Option<PathBuf>) {
+        self.bitmap_dump_dir = dir;
+    }
+
+    // Draw operations are recorded in addition to drawing them, see
+    // `vectors` module.
+    pub fn set_vector_recorder(&mut self, recorder: Option<vectors::Recorder>) {
+        self.vectors = recorder;
+    }
+
+    pub fn take_vector_recorder(&mut self) -> Option<vectors::Recorder> {
+        self.vectors.take()
+    }
+
+    fn record(&mut self, op: impl FnOnce() -> vectors::Op) {
+        if let Some(recorder) = &mut self.vectors {
+            recorder.push(op());
+        }
+    }
+
+    pub fn take_polygon_count(&mut self) -> u32 {
+        std::mem::replace(&mut self.polygon_count, 0)
+    }
+
+    pub fn current_pal_num(&self) -> Option<u8> {
+        self.current_pal_num
+    }
+
+    pub fn front_page(&self) -> u8 {
+        self.fb_xlat[1]
+    }
+
+    // Physical pages behind the logical ones: the page drawn to, the front
+    // page (0xFE) and the back page (0xFF). Swapping with 0xFF exchanges the
+    // last two.
+    pub fn page_mapping(&self) -> [u8; 3] {
+        self.fb_xlat
+    }
+
+    // Raw palette indices of one of the four game pages.
+    pub fn read_page_indexed(&self, fb: u8) -> Option<&[u8; FB_SIZE]> {
+        if fb < 4 {
+            Some(self.rndr.page(fb))
+        } else {
+            None
+        }
+    }
 }
 
 fn fetch_u8(g: &mut Game) -> u8 {
@@ -292,14 +677,22 @@ fn fetch_u16(g: &mut Game) -> u16 {
     (hi << 8) | lo
 }
 
+// When palette is forced, it's loaded instead of the requested one, so
+// the override survives invalidation and part changes.
 pub fn load_pal_mem(g: &mut Game, num: u8) {
+    let num = g.video.forced_pal_num.unwrap_or(num);
+    load_pal_unforced(g, num);
+}
+
+// Loads palette ignoring the debug override.
+pub fn load_pal_unforced(g: &mut Game, num: u8) {
     let v = &mut g.video;
     if num < 32 && v.current_pal_num != Some(num) {
         let mem = &g.mem.data[g.mem.seg_video_pal()..];
         let pal = if v.use_ega_pal {
             read_ega_pal(mem, num)
         } else {
-            read_vga_pal(mem, num)
+            read_vga_pal(mem, num, v.pal_expansion)
         };
         v.rndr.set_pal(pal);
         v.current_pal_num = Some(num);
@@ -320,15 +713,62 @@ fn read_ega_pal(mem: &[u8], num: u8) -> [RgbColor; PAL_SIZE] {
     pal
 }
 
-fn read_vga_pal(mem: &[u8], num: u8) -> [RgbColor; PAL_SIZE] {
-    let begin = usize::from(num) * PAL_SIZE * 2;
+// How VGA palette colors are stored and expanded to 8-bit components. There
+// is no detection of the edition, so it has to be picked with
+// `--pal-expansion`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PalExpansion {
+    // 0x0RGB words with every 4-bit component repeated in both nibbles.
+    Replicate,
+    // 0x0RGB words with components scaled by 255 / 15. Same as `Replicate`
+    // for 4-bit values, kept to match implementations described this way.
+    Scale,
+    // Three bytes per color with full 8-bit components.
+    Raw8Bit,
+}
+
+impl PalExpansion {
+    pub fn expand(self, component: u8) -> u8 {
+        match self {
+            PalExpansion::Replicate => (component & 0x0F) | (component << 4),
+            PalExpansion::Scale => (u16::from(component & 0x0F) * 255 / 15) as u8,
+            PalExpansion::Raw8Bit => component,
+        }
+    }
+}
+
+impl std::str::FromStr for PalExpansion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "replicate" => Ok(PalExpansion::Replicate),
+            "scale" => Ok(PalExpansion::Scale),
+            "raw8" => Ok(PalExpansion::Raw8Bit),
+            _ => Err(format!("unknown palette expansion {}", s)),
+        }
+    }
+}
+
+fn read_vga_pal(mem: &[u8], num: u8, expansion: PalExpansion) -> [RgbColor; PAL_SIZE] {
     let mut pal = [Default::default(); PAL_SIZE];
+    if expansion == PalExpansion::Raw8Bit {
+        let begin = usize::from(num) * PAL_SIZE * 3;
+        for (i, color) in pal.iter_mut().enumerate() {
+            let rgb = &mem[begin + i * 3..begin + i * 3 + 3];
+            *color = RgbColor {
+                r: rgb[0],
+                g: rgb[1],
+                b: rgb[2],
+            };
+        }
+        return pal;
+    }
+
+    let begin = usize::from(num) * PAL_SIZE * 2;
     for i in 0..PAL_SIZE {
         let color = BE::read_u16(&mem[begin + i * 2..]);
-        let extract_component = |shift: u16| {
-            let component = ((color >> shift) & 0x0F) as u8;
-            component | (component << 4)
-        };
+        let extract_component = |shift: u16| expansion.expand(((color >> shift) & 0x0F) as u8);
         pal[i] = RgbColor {
             r: extract_component(8),
             g: extract_component(4),
@@ -357,3 +797,252 @@ const EGA_PAL: [(u8, u8, u8); 16] = [
     (0xFF, 0xFF, 0x55), // bright yellow #62
     (0xFF, 0xFF, 0xFF), // bright white #63
 ];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mem::entry_kind;
+    use crate::testing;
+
+    fn page_of_text(text: &str, color: u8) -> [u8; FB_SIZE] {
+        let mut v = VideoContext::new();
+        for (i, c) in text.chars().enumerate() {
+            soft::draw_char(&mut v.rndr, 0, 16 + (i as u16) * 8, 24, c, color);
+        }
+        *v.rndr.page(0)
+    }
+
+    #[test]
+    fn string_at_edges_is_clipped() {
+        let mut v = VideoContext::new();
+        select_page(&mut v, 0);
+        // Only the top half of the first glyph fits.
+        draw_string(&mut v, 39, SCR_H - 4, 0x003, 7);
+        draw_string(&mut v, u16::MAX, u16::MAX, 0x003, 7);
+
+        let c = lookup_string(0x003).unwrap().chars().next().unwrap();
+        let mut full = VideoContext::new();
+        soft::draw_char(&mut full.rndr, 0, SCR_W - 8, SCR_H - 8, c, 7);
+        let rows = |v: &VideoContext, from: u16| {
+            let start = usize::from(from * SCR_W);
+            v.rndr.page(0)[start..start + usize::from(SCR_W) * 4].to_vec()
+        };
+        assert_eq!(rows(&v, SCR_H - 4), rows(&full, SCR_H - 8));
+        assert!(rows(&v, SCR_H - 4).contains(&7));
+        assert!(v.rndr.page(0)[..usize::from((SCR_H - 4) * SCR_W)]
+            .iter()
+            .all(|&b| b == 0));
+    }
+
+    #[test]
+    fn page_mapping_follows_selects_and_swaps() {
+        let mut v = VideoContext::new();
+        assert_eq!(v.page_mapping(), [2, 2, 1]);
+
+        select_page(&mut v, 0xFF);
+        assert_eq!(v.page_mapping(), [1, 2, 1]);
+        assert_eq!(swap_pages(&mut v, 0xFF), 1);
+        assert_eq!(v.page_mapping(), [1, 1, 2]);
+        select_page(&mut v, 0xFE);
+        assert_eq!(v.page_mapping(), [1, 1, 2]);
+        assert_eq!(swap_pages(&mut v, 0), 0);
+        assert_eq!(v.page_mapping(), [1, 0, 2]);
+        select_page(&mut v, 3);
+        assert_eq!(swap_pages(&mut v, 0xFE), 0);
+        assert_eq!(v.page_mapping(), [3, 0, 2]);
+    }
+
+    #[test]
+    fn read_page_indexed_returns_raw_pixels() {
+        let mut v = VideoContext::new();
+        fill_page(&mut v, 2, 0xB);
+
+        assert!(v.read_page_indexed(2).unwrap().iter().all(|&b| b == 0xB));
+        assert!(v.read_page_indexed(1).unwrap().iter().all(|&b| b == 0));
+        assert!(v.read_page_indexed(4).is_none());
+    }
+
+    #[test]
+    fn known_string_id_resolves() {
+        assert_eq!(lookup_string(0x001), Some("P E A N U T  3000"));
+        assert_eq!(lookup_string(0xFFFF), None);
+        assert!(strings().any(|(id, text)| id == 0x003 && text == "2"));
+    }
+
+    #[test]
+    fn dump_ppm_writes_page_colors() {
+        let mut g = testing::game("dump_ppm_writes_page_colors", &[0x06]);
+        let mut pal = [RgbColor::default(); 16];
+        pal[5] = RgbColor {
+            r: 10,
+            g: 20,
+            b: 30,
+        };
+        g.video.rndr.set_pal(pal);
+        soft::clear_fb(&mut g.video.rndr, 2, 5);
+        let path = testing::temp_dir("dump_ppm_writes_page_colors").join("page.ppm");
+
+        dump_ppm(&g, 2, &path).unwrap();
+
+        let ppm = std::fs::read(&path).unwrap();
+        let header = b"P6\n320 200\n255\n";
+        assert_eq!(&ppm[..header.len()], &header[..]);
+        assert_eq!(ppm.len(), header.len() + FB_SIZE * 3);
+        assert_eq!(&ppm[header.len()..header.len() + 3], &[10, 20, 30]);
+        assert_eq!(&ppm[ppm.len() - 3..], &[10, 20, 30]);
+    }
+
+    #[test]
+    fn palette_files_parse_back() {
+        let mut g = testing::game("palette_files_parse_back", &[0x06]);
+        let mut pal = [RgbColor::default(); 16];
+        for (i, color) in pal.iter_mut().enumerate() {
+            let i = i as u8;
+            *color = RgbColor {
+                r: i * 16,
+                g: 255 - i,
+                b: i,
+            };
+        }
+        g.video.rndr.set_pal(pal);
+        let expected: Vec<_> = pal.iter().map(|c| (c.r, c.g, c.b)).collect();
+        let dir = testing::temp_dir("palette_files_parse_back");
+
+        write_palette(&g, &dir.join("pal.gpl")).unwrap();
+        let gpl = std::fs::read_to_string(dir.join("pal.gpl")).unwrap();
+        let mut lines = gpl.lines();
+        assert_eq!(lines.next(), Some("GIMP Palette"));
+        assert_eq!(lines.next(), Some("Name: Part 16001"));
+        let colors: Vec<_> = lines
+            .skip_while(|line| *line != "#")
+            .skip(1)
+            .map(|line| {
+                let c: Vec<u8> = line
+                    .split_whitespace()
+                    .take(3)
+                    .map(|n| n.parse().unwrap())
+                    .collect();
+                (c[0], c[1], c[2])
+            })
+            .collect();
+        assert_eq!(colors, expected);
+
+        write_palette(&g, &dir.join("pal.txt")).unwrap();
+        let hex = std::fs::read_to_string(dir.join("pal.txt")).unwrap();
+        let colors: Vec<_> = hex
+            .lines()
+            .map(|line| {
+                let c = u32::from_str_radix(line, 16).unwrap();
+                ((c >> 16) as u8, (c >> 8) as u8, c as u8)
+            })
+            .collect();
+        assert_eq!(colors, expected);
+    }
+
+    #[test]
+    fn out_of_range_text_color_is_clamped() {
+        let mut v = VideoContext::new();
+        select_page(&mut v, 0);
+
+        draw_string(&mut v, 2, 24, 0x003, 0x20);
+
+        assert_eq!(v.rndr.page(0)[..], page_of_text("2", 0xF)[..]);
+    }
+
+    #[test]
+    fn pal_expansion_strategies() {
+        for &c in &[0x0, 0x7, 0xA, 0xF] {
+            assert_eq!(PalExpansion::Replicate.expand(c), c * 0x11);
+            assert_eq!(PalExpansion::Scale.expand(c), c * 0x11);
+        }
+        assert_eq!(PalExpansion::Raw8Bit.expand(0x7), 0x7);
+        assert_eq!(PalExpansion::Raw8Bit.expand(0xC8), 0xC8);
+
+        let mut words = [0; PAL_SIZE * 2];
+        BE::write_u16(&mut words[2..], 0x0A5F);
+        let pal = read_vga_pal(&words, 0, PalExpansion::Replicate);
+        assert_eq!((pal[1].r, pal[1].g, pal[1].b), (0xAA, 0x55, 0xFF));
+
+        let mut bytes = [0; PAL_SIZE * 3 * 2];
+        bytes[PAL_SIZE * 3 + 3..PAL_SIZE * 3 + 6].copy_from_slice(&[0xC8, 0x10, 0x7F]);
+        let pal = read_vga_pal(&bytes, 1, PalExpansion::Raw8Bit);
+        assert_eq!((pal[1].r, pal[1].g, pal[1].b), (0xC8, 0x10, 0x7F));
+    }
+
+    #[test]
+    fn text_transform_changes_drawn_text() {
+        let mut v = VideoContext::new();
+        v.set_text_transform(Some(Box::new(|id, text| {
+            assert_eq!(id, 0x003);
+            Cow::Owned(format!("{} ok", text).to_uppercase())
+        })));
+        select_page(&mut v, 0);
+
+        draw_string(&mut v, 2, 24, 0x003, 7);
+
+        assert_eq!(v.rndr.page(0)[..], page_of_text("2 OK", 7)[..]);
+    }
+
+    #[test]
+    fn characters_missing_from_font_are_replaced() {
+        let mut v = VideoContext::new();
+        v.set_text_transform(Some(Box::new(|_, _| Cow::Borrowed("\u{e9}\t~"))));
+        select_page(&mut v, 0);
+
+        draw_string(&mut v, 2, 24, 0x003, 7);
+
+        assert_eq!(v.rndr.page(0)[..], page_of_text("??~", 7)[..]);
+    }
+
+    #[test]
+    fn decode_bitmap_combines_planes() {
+        // Planes of 8000 bytes, 0 holding the lowest bit of colors. Each byte
+        // covers 8 pixels, leftmost in the highest bit.
+        let mut mem = vec![0; BITMAP_SIZE];
+        mem[0] = 0x80;
+        mem[8000 * 3] = 0x01;
+        mem[8000 + 40] = 0x40;
+        mem[8000 * 2 + 40] = 0x40;
+        for plane in 0..4 {
+            mem[8000 * plane + 7999] = 0x01;
+        }
+
+        let image = decode_bitmap(&mem);
+        let pixel = |x: usize, y: usize| image[y * usize::from(SCR_W) + x];
+        assert_eq!(pixel(0, 0), 1);
+        assert_eq!(pixel(7, 0), 8);
+        assert_eq!(pixel(1, 1), 6);
+        assert_eq!(pixel(319, 199), 15);
+        assert_eq!(image.iter().filter(|&&c| c != 0).count(), 4);
+    }
+
+    #[test]
+    fn nested_shape_parts_restore_dc() {
+        // Parts at 0x00 and 0x08, each with a single part pointing to the
+        // next shape, ending with a square polygon at 0x10.
+        let shapes = [
+            0x02, 0, 0, 0, 0x00, 0x04, 0, 0, //
+            0x02, 0, 0, 0, 0x00, 0x08, 0, 0, //
+            0xC5, 4, 4, 4, 4, 0, 4, 4, 0, 4, 0, 0,
+        ];
+        let m = testing::memory(
+            "nested_shape_parts_restore_dc",
+            &[
+                (testing::INTRO_PALETTE, entry_kind::PALETTE, &[0; 2048]),
+                (testing::INTRO_CODE, entry_kind::BYTECODE, &[0x06]),
+                (testing::INTRO_SHAPES, entry_kind::SHAPE, &shapes),
+            ],
+        );
+        let mut g = testing::game_with_memory(m);
+        g.video.dc = 0;
+        g.video.use_seg2 = false;
+
+        draw_shape(&mut g, 160, 100, 64, 0xFF);
+
+        // Only the top command is consumed.
+        assert_eq!(g.video.dc, 8);
+        assert!(!g.video.use_seg2);
+        let fb = g.video.fb_xlat[0];
+        assert_eq!(g.video.rndr.page(fb)[101 * usize::from(SCR_W) + 160], 5);
+    }
+}
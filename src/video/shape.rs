@@ -0,0 +1,207 @@
+// Decoding of shapes without drawing them, for tools like shape browsers.
+// Follows the same layout as `draw_shape`, but builds a tree instead.
+//
+// Dimensions are scaled by `zoom / 64` like the renderer does, so zoom 64
+// keeps them as stored.
+
+use std::convert::TryFrom;
+
+// Nesting deeper than this is treated as bad data, as it's most likely
+// a cycle.
+const MAX_DEPTH: usize = 16;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Shape {
+    // Filled polygon centered at the drawing position, or a point when it
+    // has 4 vertices, zero width and height of at most 1. `color` is only
+    // used when the caller's color has bit 7 set.
+    Polygon {
+        color: u8,
+        width: u16,
+        height: u16,
+        // Relative to the top left corner of bounding box.
+        vertices: Vec<(u16, u16)>,
+    },
+    // Group of shapes. The origin is moved up and left by `x`, `y` before
+    // positioning the parts.
+    Parts {
+        x: u16,
+        y: u16,
+        parts: Vec<Part>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    // Offset of the shape in the segment.
+    pub offset: u16,
+    pub x: u16,
+    pub y: u16,
+    // Color passed to the shape, if set by the part.
+    pub color: Option<u8>,
+    pub shape: Shape,
+}
+
+struct Cursor<'a> {
+    seg: &'a [u8],
+    pos: usize,
+    zoom: u16,
+}
+
+impl Cursor<'_> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let b = *self
+            .seg
+            .get(self.pos)
+            .ok_or_else(|| format!("shape data ends at 0x{:04X}", self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let hi = u16::from(self.u8()?);
+        let lo = u16::from(self.u8()?);
+        Ok((hi << 8) | lo)
+    }
+
+    // Dimension scaled by zoom.
+    fn dim(&mut self) -> Result<u16, String> {
+        let dim = u32::from(self.u8()?) * u32::from(self.zoom) / 64;
+        u16::try_from(dim).map_err(|_| format!("zoom {} too large", self.zoom))
+    }
+}
+
+// Decodes the shape at given offset of a video segment.
+pub fn decode(seg: &[u8], offset: u16, zoom: u16) -> Result<Shape, String> {
+    decode_at(seg, offset, zoom, 0)
+}
+
+fn decode_at(seg: &[u8], offset: u16, zoom: u16, depth: usize) -> Result<Shape, String> {
+    if depth > MAX_DEPTH {
+        return Err(format!("shapes nested too deep at 0x{:04X}", offset));
+    }
+    let mut c = Cursor {
+        seg,
+        pos: usize::from(offset),
+        zoom,
+    };
+    let i = c.u8()?;
+    if i >= 0xC0 {
+        let width = c.dim()?;
+        let height = c.dim()?;
+        let num = c.u8()?;
+        if (num & 1) != 0 {
+            return Err(format!("unexpected number of vertices {}", num));
+        }
+        let vertices = (0..num)
+            .map(|_| Ok((c.dim()?, c.dim()?)))
+            .collect::<Result<_, String>>()?;
+        Ok(Shape::Polygon {
+            color: i & 0x3F,
+            width,
+            height,
+            vertices,
+        })
+    } else if (i & 0x3F) == 2 {
+        let x = c.dim()?;
+        let y = c.dim()?;
+        let n = c.u8()?;
+        let mut parts = Vec::new();
+        for _ in 0..=n {
+            let offset = c.u16()?;
+            let x = c.dim()?;
+            let y = c.dim()?;
+            let color = if (offset & 0x8000) != 0 {
+                let hi = c.u8()?;
+                let _lo = c.u8()?;
+                Some(hi & 0x7F)
+            } else {
+                None
+            };
+            let offset = offset << 1;
+            let shape = decode_at(seg, offset, zoom, depth + 1)?;
+            parts.push(Part {
+                offset,
+                x,
+                y,
+                color,
+                shape,
+            });
+        }
+        Ok(Shape::Parts { x, y, parts })
+    } else {
+        Err(format!("invalid video op {}", i & 0x3F))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two parts using the same polygon at 0x10, the second with its own
+    // color.
+    const SEGMENT: [u8; 28] = [
+        0x02, 10, 20, 1, //
+        0x00, 0x08, 5, 6, //
+        0x80, 0x08, 7, 8, 0x83, 0x00, //
+        0, 0, //
+        0xC5, 4, 2, 4, 4, 0, 4, 2, 0, 2, 0, 0,
+    ];
+
+    fn polygon(scale: u16) -> Shape {
+        Shape::Polygon {
+            color: 5,
+            width: 4 * scale,
+            height: 2 * scale,
+            vertices: vec![
+                (4 * scale, 0),
+                (4 * scale, 2 * scale),
+                (0, 2 * scale),
+                (0, 0),
+            ],
+        }
+    }
+
+    #[test]
+    fn decodes_parts_tree() {
+        let shape = decode(&SEGMENT, 0, 64).unwrap();
+        let expected = Shape::Parts {
+            x: 10,
+            y: 20,
+            parts: vec![
+                Part {
+                    offset: 0x10,
+                    x: 5,
+                    y: 6,
+                    color: None,
+                    shape: polygon(1),
+                },
+                Part {
+                    offset: 0x10,
+                    x: 7,
+                    y: 8,
+                    color: Some(3),
+                    shape: polygon(1),
+                },
+            ],
+        };
+        assert_eq!(shape, expected);
+    }
+
+    #[test]
+    fn zoom_scales_dimensions() {
+        match decode(&SEGMENT, 0, 128).unwrap() {
+            Shape::Parts { x, y, parts } => {
+                assert_eq!((x, y), (20, 40));
+                assert_eq!((parts[1].x, parts[1].y), (14, 16));
+                assert_eq!(parts[1].shape, polygon(2));
+            }
+            shape => panic!("unexpected {:?}", shape),
+        }
+    }
+
+    #[test]
+    fn truncated_data_is_an_error() {
+        assert!(decode(&SEGMENT[..20], 0, 64).is_err());
+    }
+}
@@ -9,8 +9,44 @@ const COL_PAGE: u8 = 0x11;
 
 pub const FB_SIZE: usize = (SCR_W * SCR_H) as usize;
 
+// Scratch page for debug overlays, never used by the game.
+pub const OVERLAY_FB: u8 = 4;
+
+const GAMMA_MIN: f32 = 0.25;
+const GAMMA_MAX: f32 = 4.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Rgb565,
+    Argb8888,
+    // Page indices are uploaded as is and SDL applies the palette.
+    Indexed8,
+}
+
+impl std::str::FromStr for PixelFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rgb565" => Ok(PixelFormat::Rgb565),
+            "argb8888" => Ok(PixelFormat::Argb8888),
+            "indexed8" => Ok(PixelFormat::Indexed8),
+            _ => Err(format!("unknown pixel format {}", s)),
+        }
+    }
+}
+
 pub struct State {
-    fb: Box<[[u8; FB_SIZE]; 4]>,
+    fb: Box<[[u8; FB_SIZE]; 5]>,
+    pal: [RgbColor; 16],
+    gamma: f32,
+    // Displayed color index for every drawn one, applied when reading pixels.
+    remap: Option<[u8; 16]>,
+}
+
+// Page contents together with the palette it was displayed with.
+pub struct PageSnapshot {
+    pixels: Box<[u8; FB_SIZE]>,
     pal: [RgbColor; 16],
 }
 
@@ -25,20 +61,20 @@ pub fn copy_fb(s: &mut State, dst_fb: u8, src_fb: u8, v_scroll: i32) {
     assert_ne!(dst_fb, src_fb);
     let mut dst = s.fb[usize::from(dst_fb)].as_mut_ptr();
     let mut src = s.fb[usize::from(src_fb)].as_ptr();
-    let count = if -199 <= v_scroll && v_scroll <= 199 {
+    // Scrolling up skips top rows of the source, scrolling down moves the
+    // destination down, rows pushed out at the bottom are dropped.
+    let rows = scrolled_rows(v_scroll, i32::from(SCR_H));
+    let count = if rows > 0 {
         if v_scroll < 0 {
             unsafe {
                 src = src.add((-v_scroll as usize) * usize::from(SCR_W));
             }
-            (i32::from(SCR_H) + v_scroll) * i32::from(SCR_W)
         } else if v_scroll > 0 {
             unsafe {
                 dst = dst.add((v_scroll as usize) * usize::from(SCR_W));
             }
-            (i32::from(SCR_H) - v_scroll) * i32::from(SCR_W)
-        } else {
-            i32::from(SCR_W * SCR_H)
         }
+        rows * i32::from(SCR_W)
     } else {
         0
     };
@@ -48,6 +84,12 @@ pub fn copy_fb(s: &mut State, dst_fb: u8, src_fb: u8, v_scroll: i32) {
     }
 }
 
+// Rows left to copy when scrolling a page of `height` rows by `v_scroll`.
+// Scrolling by whole height or more leaves nothing to copy.
+fn scrolled_rows(v_scroll: i32, height: i32) -> i32 {
+    (height - v_scroll.abs()).max(0)
+}
+
 pub fn draw_point(s: &mut State, fb: u8, x: u16, y: u16, color: u8) {
     let color = match color {
         COL_ALPHA => grab(s, fb, x, y) | 8,
@@ -161,13 +203,18 @@ fn draw_h_line_color(s: &mut State, fb: u8, offset: usize, w: u16, color: u8) {
     }
 }
 
+// Glyphs crossing the right or bottom edge are clipped.
 pub fn draw_char(s: &mut State, fb: u8, x: u16, y: u16, c: char, color: u8) {
-    if x <= SCR_W - 8 && y <= SCR_H - 8 {
-        let glyph = (u32::from(c) - 0x20) * 8;
-        for j in 0..8 {
-            let line = data::FONT[(glyph as usize) + usize::from(j)];
-            for i in (0..8).filter(|i| pixel_in_font_line(line, *i)) {
-                out(s, fb, x + u16::from(i), y + j, color);
+    let glyph = (u32::from(c) - 0x20) * 8;
+    for j in 0..8 {
+        let y = match y.checked_add(j).filter(|y| *y < SCR_H) {
+            Some(y) => y,
+            None => break,
+        };
+        let line = data::FONT[(glyph as usize) + usize::from(j)];
+        for i in (0..8).filter(|i| pixel_in_font_line(line, *i)) {
+            if let Some(x) = x.checked_add(u16::from(i)).filter(|x| *x < SCR_W) {
+                out(s, fb, x, y, color);
             }
         }
     }
@@ -190,23 +237,119 @@ fn grab(s: &mut State, fb: u8, x: u16, y: u16) -> u8 {
     s.fb[usize::from(fb)][usize::from(y * SCR_W + x)]
 }
 
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl State {
     pub fn new() -> Self {
         Self {
-            fb: Box::new([[0; FB_SIZE], [0; FB_SIZE], [0; FB_SIZE], [0; FB_SIZE]]),
+            fb: Box::new([[0; FB_SIZE]; 5]),
             pal: Default::default(),
+            gamma: 1.0,
+            remap: None,
         }
     }
 
+    pub fn page(&self, fb: u8) -> &[u8; FB_SIZE] {
+        &self.fb[usize::from(fb)]
+    }
+
     pub fn read_pixels(&self, fb: u8, out: &mut [u16]) {
+        self.read_converted(fb, out, RgbColor::as_rgb565);
+    }
+
+    pub fn read_argb8888(&self, fb: u8, out: &mut [u32]) {
+        self.read_converted(fb, out, RgbColor::as_argb8888);
+    }
+
+    pub fn snapshot(&self, fb: u8) -> PageSnapshot {
+        PageSnapshot {
+            pixels: Box::new(self.fb[usize::from(fb)]),
+            pal: self.pal,
+        }
+    }
+
+    // Like `read_pixels`, but mixed with the snapshot. `weight` of 255 shows
+    // only the snapshot, 0 only the page.
+    pub fn read_pixels_blended(&self, fb: u8, from: &PageSnapshot, weight: u8, out: &mut [u16]) {
+        self.read_blended(fb, from, weight, out, RgbColor::as_rgb565);
+    }
+
+    pub fn read_argb8888_blended(&self, fb: u8, from: &PageSnapshot, weight: u8, out: &mut [u32]) {
+        self.read_blended(fb, from, weight, out, RgbColor::as_argb8888);
+    }
+
+    fn read_blended<T>(
+        &self,
+        fb: u8,
+        from: &PageSnapshot,
+        weight: u8,
+        out: &mut [T],
+        convert: fn(RgbColor) -> T,
+    ) {
+        let src = self.fb[usize::from(fb)].iter().zip(from.pixels.iter());
+        for (i, (new, old)) in src.enumerate() {
+            let new = self.pal[self.remapped(*new)];
+            let old = from.pal[self.remapped(*old)];
+            out[i] = convert(old.mix(new, weight));
+        }
+    }
+
+    fn read_converted<T>(&self, fb: u8, out: &mut [T], convert: fn(RgbColor) -> T) {
         let src = &self.fb[usize::from(fb)];
         for (i, pixel) in src.iter().enumerate() {
-            out[i] = self.pal[usize::from(*pixel)].as_rgb565();
+            out[i] = convert(self.pal[self.remapped(*pixel)]);
+        }
+    }
+
+    fn remapped(&self, index: u8) -> usize {
+        match &self.remap {
+            Some(remap) => usize::from(remap[usize::from(index & 0xF)]),
+            None => usize::from(index),
         }
     }
 
+    // Indices out of palette range are rejected.
+    pub fn set_remap(&mut self, remap: Option<[u8; 16]>) -> Result<(), String> {
+        if let Some(bad) = remap.iter().flatten().find(|i| **i >= 16) {
+            return Err(format!("palette index {} out of range", bad));
+        }
+        self.remap = remap;
+        Ok(())
+    }
+
+    pub fn palette(&self) -> &[RgbColor; 16] {
+        &self.pal
+    }
+
+    // Palette with index remapping applied, for indexed output.
+    pub fn display_palette(&self) -> [RgbColor; 16] {
+        let mut pal = self.pal;
+        for (i, color) in pal.iter_mut().enumerate() {
+            *color = self.pal[self.remapped(i as u8)];
+        }
+        pal
+    }
+
     pub fn set_pal(&mut self, pal: [RgbColor; 16]) {
+        let gamma = self.gamma;
         self.pal = pal;
+        for color in self.pal.iter_mut() {
+            *color = color.with_gamma(gamma);
+        }
+    }
+
+    // Takes effect on next palette load. Values out of range are clamped,
+    // NaN and non-positive ones are rejected.
+    pub fn set_gamma(&mut self, gamma: f32) -> Result<(), String> {
+        if gamma.is_nan() || gamma <= 0.0 {
+            return Err(format!("gamma must be positive, got {}", gamma));
+        }
+        self.gamma = gamma.clamp(GAMMA_MIN, GAMMA_MAX);
+        Ok(())
     }
 }
 
@@ -217,4 +360,190 @@ impl RgbColor {
         let b = u16::from(self.b) >> 3;
         r | g | b
     }
+
+    fn as_argb8888(self) -> u32 {
+        0xFF00_0000 | (u32::from(self.r) << 16) | (u32::from(self.g) << 8) | u32::from(self.b)
+    }
+
+    fn mix(self, other: Self, weight: u8) -> Self {
+        let mix = |a: u8, b: u8| {
+            let w = u16::from(weight);
+            ((u16::from(a) * w + u16::from(b) * (255 - w)) / 255) as u8
+        };
+        Self {
+            r: mix(self.r, other.r),
+            g: mix(self.g, other.g),
+            b: mix(self.b, other.b),
+        }
+    }
+
+    // Values above 1.0 brighten dark colors, below 1.0 darken them.
+    fn with_gamma(self, gamma: f32) -> Self {
+        let correct = |c: u8| ((f32::from(c) / 255.0).powf(1.0 / gamma) * 255.0).round() as u8;
+        Self {
+            r: correct(self.r),
+            g: correct(self.g),
+            b: correct(self.b),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Page 1 with each row filled with its number, page 0 with 0xFF.
+    fn rows_state() -> State {
+        let mut s = State::new();
+        clear_fb(&mut s, 0, 0xFF);
+        for (y, row) in s.fb[1].chunks_exact_mut(usize::from(SCR_W)).enumerate() {
+            for b in row.iter_mut() {
+                *b = y as u8;
+            }
+        }
+        s
+    }
+
+    fn row(s: &State, fb: u8, y: usize) -> &[u8] {
+        &s.page(fb)[y * usize::from(SCR_W)..(y + 1) * usize::from(SCR_W)]
+    }
+
+    #[test]
+    fn copy_fb_scrolls_down() {
+        let mut s = rows_state();
+        copy_fb(&mut s, 0, 1, 40);
+        for y in 0..40 {
+            assert!(row(&s, 0, y).iter().all(|&b| b == 0xFF), "row {}", y);
+        }
+        for y in 40..usize::from(SCR_H) {
+            assert!(
+                row(&s, 0, y).iter().all(|&b| b == (y - 40) as u8),
+                "row {}",
+                y
+            );
+        }
+    }
+
+    #[test]
+    fn copy_fb_scrolls_up() {
+        let mut s = rows_state();
+        copy_fb(&mut s, 0, 1, -40);
+        for y in 0..160 {
+            assert!(
+                row(&s, 0, y).iter().all(|&b| b == (y + 40) as u8),
+                "row {}",
+                y
+            );
+        }
+        for y in 160..usize::from(SCR_H) {
+            assert!(row(&s, 0, y).iter().all(|&b| b == 0xFF), "row {}", y);
+        }
+    }
+
+    #[test]
+    fn scroll_clamp_follows_height() {
+        assert_eq!(scrolled_rows(0, 200), 200);
+        assert_eq!(scrolled_rows(199, 200), 1);
+        assert_eq!(scrolled_rows(-199, 200), 1);
+        assert_eq!(scrolled_rows(200, 200), 0);
+        assert_eq!(scrolled_rows(300, 200), 0);
+        assert_eq!(scrolled_rows(300, 400), 100);
+        assert_eq!(scrolled_rows(-399, 400), 1);
+        assert_eq!(scrolled_rows(-400, 400), 0);
+    }
+
+    #[test]
+    fn copy_fb_ignores_scroll_past_screen() {
+        let mut s = rows_state();
+        copy_fb(&mut s, 0, 1, i32::from(SCR_H));
+        copy_fb(&mut s, 0, 1, -i32::from(SCR_H));
+        assert!(s.page(0).iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn pixel_formats_match() {
+        let mut s = State::new();
+        let mut pal = [RgbColor::default(); 16];
+        for (i, color) in pal.iter_mut().enumerate() {
+            let i = i as u8;
+            *color = RgbColor {
+                r: i * 17,
+                g: 255 - i * 13,
+                b: i * 5,
+            };
+        }
+        s.set_pal(pal);
+        for (y, row) in s.fb[0].chunks_exact_mut(usize::from(SCR_W)).enumerate() {
+            for (x, b) in row.iter_mut().enumerate() {
+                *b = ((x + y) % 16) as u8;
+            }
+        }
+
+        let mut rgb565 = vec![0; FB_SIZE];
+        let mut argb = vec![0; FB_SIZE];
+        s.read_pixels(0, &mut rgb565);
+        s.read_argb8888(0, &mut argb);
+        for (&p, &q) in rgb565.iter().zip(argb.iter()) {
+            let (r, g, b) = ((q >> 16) & 0xFF, (q >> 8) & 0xFF, q & 0xFF);
+            assert_eq!(q >> 24, 0xFF);
+            assert_eq!(u32::from(p >> 11), r >> 3);
+            assert_eq!(u32::from((p >> 5) & 0x3F), g >> 2);
+            assert_eq!(u32::from(p & 0x1F), b >> 3);
+        }
+        assert_eq!(argb[1], 0xFF11_F205);
+    }
+
+    #[test]
+    fn remap_applies_before_palette_lookup() {
+        let mut s = State::new();
+        let mut pal = [RgbColor::default(); 16];
+        for (i, color) in pal.iter_mut().enumerate() {
+            color.r = (i * 16) as u8;
+        }
+        s.set_pal(pal);
+        clear_fb(&mut s, 0, 4);
+        let mut remap = [0; 16];
+        for (i, index) in remap.iter_mut().enumerate() {
+            *index = i as u8;
+        }
+        remap[4] = 12;
+
+        let mut before = vec![0; FB_SIZE];
+        let mut after = vec![0; FB_SIZE];
+        s.read_pixels(0, &mut before);
+        s.set_remap(Some(remap)).unwrap();
+        s.read_pixels(0, &mut after);
+
+        assert!(before.iter().all(|&p| p >> 11 == 64 >> 3));
+        assert!(after.iter().all(|&p| p >> 11 == 192 >> 3));
+        assert!(s.set_remap(Some([16; 16])).is_err());
+    }
+
+    #[test]
+    fn gamma_brightens_palette() {
+        let mut s = State::new();
+        s.set_gamma(2.2).unwrap();
+        let mut pal = [RgbColor::default(); 16];
+        pal[1] = RgbColor {
+            r: 64,
+            g: 0,
+            b: 255,
+        };
+        s.set_pal(pal);
+
+        // (64 / 255) ^ (1 / 2.2) * 255 = 136.03
+        assert_eq!(s.palette()[1].r, 136);
+        assert_eq!(s.palette()[1].g, 0);
+        assert_eq!(s.palette()[1].b, 255);
+    }
+
+    #[test]
+    fn gamma_rejects_nan_and_non_positive() {
+        let mut s = State::new();
+        s.set_gamma(2.0).unwrap();
+        assert!(s.set_gamma(f32::NAN).is_err());
+        assert!(s.set_gamma(0.0).is_err());
+        assert!(s.set_gamma(-1.0).is_err());
+        assert_eq!(s.gamma, 2.0);
+    }
 }
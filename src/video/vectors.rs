@@ -0,0 +1,201 @@
+// Recording of draw operations for `--export-vectors`, keeping the game's
+// vector art independent of resolution and palette.
+//
+// Every displayed frame is written as a line of JSON:
+//
+//   {"frame":0,"page":2,"ops":[{"op":"fill","page":2,"color":0},...]}
+//
+// Operations are listed in the order they were drawn, since later ones
+// cover earlier ones, and pages are the physical pages 0 to 3. Coordinates
+// are in the 320x200 screen after zooming. Polygon vertices are in stored
+// order, which goes down the right side and up the left side. Colors above
+// 15 are the special ones of `soft::draw_polygon`: 16 brightens what's
+// below, 17 copies from page 0. Bitmaps are referenced by resource number.
+
+use super::Vertex;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+pub enum Op {
+    Fill {
+        page: u8,
+        color: u8,
+    },
+    Copy {
+        src: u8,
+        dst: u8,
+        v_scroll: i16,
+    },
+    Polygon {
+        page: u8,
+        color: u8,
+        vertices: Vec<Vertex>,
+    },
+    Point {
+        page: u8,
+        x: i16,
+        y: i16,
+        color: u8,
+    },
+    Text {
+        page: u8,
+        x: u16,
+        y: u16,
+        color: u8,
+        text: String,
+    },
+    Bitmap {
+        page: u8,
+        res_num: usize,
+    },
+}
+
+pub struct Recorder {
+    out: BufWriter<File>,
+    frame: u64,
+    ops: Vec<Op>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            out: BufWriter::new(File::create(path)?),
+            frame: 0,
+            ops: Vec::new(),
+        })
+    }
+
+    pub fn push(&mut self, op: Op) {
+        self.ops.push(op);
+    }
+
+    // Writes operations recorded since the previous frame, `page` being
+    // the one displayed.
+    pub fn end_frame(&mut self, page: u8) -> io::Result<()> {
+        let mut line = format!(r#"{{"frame":{},"page":{},"ops":["#, self.frame, page);
+        for (i, op) in self.ops.drain(..).enumerate() {
+            if i != 0 {
+                line.push(',');
+            }
+            write_op(&mut line, &op);
+        }
+        line.push_str("]}");
+        self.frame += 1;
+        writeln!(self.out, "{}", line)
+    }
+
+    // Operations drawn after the last displayed frame are dropped.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+fn write_op(s: &mut String, op: &Op) {
+    // Writing to a `String` can't fail.
+    let _ = match op {
+        Op::Fill { page, color } => {
+            write!(s, r#"{{"op":"fill","page":{},"color":{}}}"#, page, color)
+        }
+        Op::Copy { src, dst, v_scroll } => write!(
+            s,
+            r#"{{"op":"copy","src":{},"dst":{},"v_scroll":{}}}"#,
+            src, dst, v_scroll
+        ),
+        Op::Polygon {
+            page,
+            color,
+            vertices,
+        } => {
+            let _ = write!(
+                s,
+                r#"{{"op":"polygon","page":{},"color":{},"vertices":["#,
+                page, color
+            );
+            for (i, v) in vertices.iter().enumerate() {
+                let sep = if i == 0 { "" } else { "," };
+                let _ = write!(s, "{}[{},{}]", sep, v.x, v.y);
+            }
+            write!(s, "]}}")
+        }
+        Op::Point { page, x, y, color } => write!(
+            s,
+            r#"{{"op":"point","page":{},"x":{},"y":{},"color":{}}}"#,
+            page, x, y, color
+        ),
+        Op::Text {
+            page,
+            x,
+            y,
+            color,
+            text,
+        } => write!(
+            s,
+            r#"{{"op":"text","page":{},"x":{},"y":{},"color":{},"text":"{}"}}"#,
+            page,
+            x,
+            y,
+            color,
+            escape(text)
+        ),
+        Op::Bitmap { page, res_num } => {
+            write!(s, r#"{{"op":"bitmap","page":{},"res":{}}}"#, page, res_num)
+        }
+    };
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if c < ' ' => {
+                let _ = write!(escaped, "\\u{:04x}", u32::from(c));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn frames_list_ops_in_draw_order() {
+        let code = [
+            0x0D, 0x00, // fb_sel 0
+            0x0E, 0x00, 0x03, // fb_fill 0, 3
+            0x0F, 0x00, 0x01, // fb_copy 0, 1
+            0x10, 0x00, // swap 0
+            0x06, 0x07, 0x00, 0x00, // yield; b 0x0000
+        ];
+        let mut g = testing::game("frames_list_ops_in_draw_order", &code);
+        let path = testing::temp_dir("frames_list_ops_in_draw_order_out").join("vectors.json");
+        g.video
+            .set_vector_recorder(Some(Recorder::create(&path).unwrap()));
+
+        crate::run_frame(&mut g);
+        crate::run_frame(&mut g);
+        g.video.take_vector_recorder().unwrap().finish().unwrap();
+
+        let text = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<_> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for (i, line) in lines.iter().enumerate() {
+            assert_eq!(
+                *line,
+                format!(
+                    r#"{{"frame":{},"page":0,"ops":[{}]}}"#,
+                    i,
+                    r#"{"op":"fill","page":0,"color":3},{"op":"copy","src":0,"dst":1,"v_scroll":0}"#
+                )
+            );
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Watching game data for changes, to reload current part while modding
+// with `--watch-data`.
+//
+// Files are polled for modification time, as there is no portable way to
+// get notified about changes. Reloading restarts current part, so the VM
+// state including registers set by previous parts is reset, much like when
+// the part is entered again. Unpacked entries kept by `--cache-dir` are
+// looked up by bank position and size, so it's best left off while editing
+// banks.
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Watcher {
+    dirs: Vec<PathBuf>,
+    // Modification time of every file seen in watched directories.
+    files: Vec<(PathBuf, Option<SystemTime>)>,
+    last_poll: Instant,
+}
+
+impl Watcher {
+    pub fn new(dirs: Vec<PathBuf>) -> Self {
+        let files = scan(&dirs);
+        Self {
+            dirs,
+            files,
+            last_poll: Instant::now(),
+        }
+    }
+
+    // Whether any file was added, removed or modified since the last change
+    // was reported. Directories are scanned at most once per second.
+    pub fn poll(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        let files = scan(&self.dirs);
+        if files == self.files {
+            return false;
+        }
+        for (path, _) in files.iter().filter(|f| !self.files.contains(f)) {
+            log::info!("{} changed", path.display());
+        }
+        self.files = files;
+        true
+    }
+}
+
+// Only files which may be read as game data are watched, so screenshots and
+// other files written next to them don't trigger reloads.
+fn is_data_file(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    name.starts_with("bank")
+        || name == "memlist.bin"
+        || name.ends_with(".pak")
+        || name.parse::<u16>().is_ok()
+        || name.starts_with("0x")
+}
+
+fn scan(dirs: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let mut files: Vec<_> = dirs.iter().flat_map(|dir| scan_dir(dir)).collect();
+    files.sort();
+    files
+}
+
+fn scan_dir(dir: &Path) -> Vec<(PathBuf, Option<SystemTime>)> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            log::warn!("unable to watch {}: {}", dir.display(), e);
+            return Vec::new();
+        }
+    };
+    entries
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_ok_and(|t| t.is_file()))
+        .filter(|e| e.file_name().to_str().is_some_and(is_data_file))
+        .map(|e| {
+            let modified = e.metadata().and_then(|m| m.modified()).ok();
+            (e.path(), modified)
+        })
+        .collect()
+}